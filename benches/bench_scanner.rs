@@ -0,0 +1,43 @@
+use cloxers::scanner::Scanner;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+/// A little of everything the scanner handles, repeated to make a few megabytes of source.
+const SNIPPET: &str = r#"
+// a comment, to be skipped
+class Counter < Base {
+  init(start) { this.count = start; }
+  step() { this.count = this.count + 1.5; return this.count >= 100 and !false; }
+}
+var counter = Counter("start");
+while (counter.step() != nil) print "tick, tock";
+"#;
+
+fn source() -> String {
+    SNIPPET.repeat(4 * 1024 * 1024 / SNIPPET.len())
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let source = source();
+    let mut group = c.benchmark_group("scan 4MB");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.sample_size(20);
+    // Pulling tokens one at a time, as the compiler does, keeps just one in memory.
+    group.bench_function("streaming", |b| {
+        b.iter(|| Scanner::new(&source).filter(Result::is_ok).count())
+    });
+    group.bench_function("scan_tokens", |b| {
+        b.iter(|| Scanner::new(&source).scan_tokens().unwrap().len())
+    });
+    // Lexemes are borrowed from the source; copying each one out shows what that saves.
+    group.bench_function("owned lexemes", |b| {
+        b.iter(|| {
+            for token in Scanner::new(&source) {
+                black_box(token.unwrap().into_owned());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
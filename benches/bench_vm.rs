@@ -1,24 +1,64 @@
-use criterion::{criterion_group, criterion_main, Criterion};
 use cloxers::chunk::Chunk;
 use cloxers::opcodes::OpCode;
-use cloxers::vm::VM;
 use cloxers::value::Value;
+use cloxers::vm::VM;
+use cloxers::Interpreter;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+/// Which `Value` layout is being measured. To compare them, save a baseline with one and
+/// check the other against it:
+///
+///     cargo bench --bench bench_vm -- --save-baseline enum
+///     cargo bench --bench bench_vm --features nan-boxing -- --baseline enum
+///
+/// Benchmark names are the same in both builds so that criterion lines them up.
+const LAYOUT: &str = if cfg!(feature = "nan-boxing") {
+    "nan-boxed"
+} else {
+    "enum"
+};
 
+const FIB: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+print fib(20);
+";
+
+const LOOP: &str = "
+var sum = 0;
+var flag = false;
+for (var i = 0; i < 100000; i = i + 1) {
+  sum = sum + i * 2 - 1;
+  flag = !flag and i > 10 or nil;
+}
+print sum;
+";
 
 fn run_arithmetic() {
     let mut chunk = Chunk::new();
-    let _ = chunk.write_constant(Value::Number(1.2), 1);
-    let _ = chunk.write_constant(Value::Number(3.4), 1);
-    let _ = chunk.write(OpCode::Add.into(), 1);
-    let _ = chunk.write_constant(Value::Number(5.6), 2);
-    let _ = chunk.write(OpCode::Divide.into(), 4);
-    chunk.write(OpCode::Return.into(),2);
-    VM::new(&chunk).run().unwrap();
+    let _ = chunk.write_constant(Value::number(1.2), 1);
+    let _ = chunk.write_constant(Value::number(3.4), 1);
+    chunk.write(OpCode::Add.into(), 1);
+    let _ = chunk.write_constant(Value::number(5.6), 2);
+    chunk.write(OpCode::Divide.into(), 4);
+    chunk.write(OpCode::Return.into(), 2);
+    let mut vm = VM::new();
+    vm.interpret_chunk(chunk).unwrap();
+}
+
+fn run_source(source: &str) {
+    let mut interpreter = Interpreter::with_output(Box::new(std::io::sink()));
+    interpreter.run(source).unwrap();
 }
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("arithmetic 20", |b| b.iter(|| run_arithmetic()));
+    eprintln!("benchmarking the {} value layout", LAYOUT);
+    c.bench_function("arithmetic 20", |b| b.iter(run_arithmetic));
+    c.bench_function("fib 20", |b| b.iter(|| run_source(FIB)));
+    c.bench_function("numeric loop", |b| b.iter(|| run_source(LOOP)));
 }
 
 criterion_group!(benches, criterion_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
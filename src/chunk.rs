@@ -1,11 +1,57 @@
+use std::collections::HashMap;
 use std::fmt::Write;
 
-use miette::{miette, Context, IntoDiagnostic, Result};
+use miette::{miette, Context, IntoDiagnostic, Result, SourceSpan};
 
 use crate::error::CloxersError;
+use crate::object::ObjRef;
 use crate::opcodes::OpCode;
 use crate::value::Value;
 
+/// `ConstantLong` and the other long instructions index the constant pool with a 24-bit operand.
+const MAX_CONSTANTS: usize = 1 << 24;
+
+/// What makes two constants interchangeable. `Value`'s `PartialEq` follows Lox semantics, which
+/// is wrong here: numbers are compared by bit pattern instead, so that `0.0` and `-0.0` are kept
+/// apart while NaN matches itself. Objects are compared by identity (strings are interned).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Bool(bool),
+    Nil,
+    Obj(ObjRef),
+}
+
+impl From<&Value> for ConstantKey {
+    fn from(value: &Value) -> Self {
+        if let Some(n) = value.as_number() {
+            // Every NaN is the same constant, whatever its payload.
+            let n = if n.is_nan() { f64::NAN } else { n };
+            ConstantKey::Number(n.to_bits())
+        } else if let Some(b) = value.as_bool() {
+            ConstantKey::Bool(b)
+        } else if let Some(obj) = value.as_obj() {
+            ConstantKey::Obj(obj)
+        } else {
+            ConstantKey::Nil
+        }
+    }
+}
+
+/// The first byte of a run of bytecode compiled from the same source line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+/// The source code of the instruction starting at `offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InstructionSpan {
+    offset: usize,
+    span: SourceSpan,
+}
+
 /// A chunk of bytecode.
 /// This struct implements a custom IntoIterator so we can iterate over OpCodes *only*
 /// and not the operands.
@@ -15,7 +61,14 @@ pub struct Chunk {
     // We will use a Vec<u8> to store the bytecode instead.
     code: Vec<u8>,
     constants: Vec<Value>,
-    lines: Vec<usize>, // line numbers for debugging
+    // index of each constant in `constants`, so that adding it again reuses the slot
+    constant_indices: HashMap<ConstantKey, usize>,
+    // Line numbers for debugging, run-length encoded: consecutive bytes mostly come from the
+    // same line, so only the offsets where the line changes are stored.
+    lines: Vec<LineStart>,
+    // Source code for runtime errors to point at, in order of offset. Only instructions which
+    // can fail have one, so this stays sparse.
+    spans: Vec<InstructionSpan>,
 }
 
 impl Default for Chunk {
@@ -29,50 +82,140 @@ impl Chunk {
         Self {
             code: Vec::new(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
         }
     }
 
+    /// The bytecode written so far.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// The source line of the byte at `offset`.
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        if offset >= self.code.len() {
+            return None;
+        }
+        // the last run starting at or before `offset`
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        Some(self.lines[run - 1].line)
+    }
+
+    /// The bytes of source code the instruction containing the byte at `offset` was compiled
+    /// from, if it was given any.
+    pub fn span_at(&self, offset: usize) -> Option<SourceSpan> {
+        // the last instruction with a span starting at or before `offset`
+        let index = self.spans.partition_point(|start| start.offset <= offset);
+        let InstructionSpan {
+            offset: start,
+            span,
+        } = *self.spans.get(index.checked_sub(1)?)?;
+        let op_code = OpCode::try_from(*self.code.get(start)?).ok()?;
+        // `offset` may belong to a later instruction without a span of its own
+        (offset <= start + op_code.operand_offset()).then_some(span)
+    }
+
+    /// Overwrites an already-written byte: used to backpatch jump offsets.
+    pub fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    /// The constant pool.
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
     pub fn read_constant(&self, offset: usize) -> Option<&Value> {
         self.constants.get(offset)
     }
 
+    /// The index of a constant identical to `value`, if the pool already has one.
+    pub fn find_constant(&self, value: &Value) -> Option<usize> {
+        self.constant_indices
+            .get(&ConstantKey::from(value))
+            .copied()
+    }
+
     /// Writes a byte to the chunk: may be opcode or operand.
     pub fn write(&mut self, byte: u8, line: usize) {
+        if self.lines.last().map(|start| start.line) != Some(line) {
+            self.lines.push(LineStart {
+                offset: self.code.len(),
+                line,
+            });
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
-    /// Writes a constant to the chunk: opcode followed by operand's index (as u8) in constants vec.
+    /// Records the source code of the instruction written next, for runtime errors in it to
+    /// point at.
+    pub fn write_span(&mut self, span: SourceSpan) {
+        self.spans.push(InstructionSpan {
+            offset: self.code.len(),
+            span,
+        });
+    }
+
+    /// Writes a constant to the chunk: opcode followed by operand's index in constants vec.
+    /// The first 256 constants take a one-byte operand; after that, `ConstantLong` takes a
+    /// big-endian 24-bit one.
     pub fn write_constant(&mut self, value: Value, line: usize) -> Result<()> {
-        // Is this an off-by-one error?
-        if self.constants.len() > (u8::MAX as usize) {
-            return Err(CloxersError::ConstantsOverflowed).into_diagnostic();
+        let index = self.try_add_constant(value)?;
+        match u8::try_from(index) {
+            Ok(index) => {
+                self.write(OpCode::Constant.into(), line);
+                self.write(index, line);
+            }
+            Err(_) => {
+                let [_, high, mid, low] = (index as u32).to_be_bytes();
+                self.write(OpCode::ConstantLong.into(), line);
+                self.write(high, line);
+                self.write(mid, line);
+                self.write(low, line);
+            }
         }
-        let index = self.add_constant(value);
-        let index = index
-            .try_into()
-            .map_err(|_| CloxersError::OpCodeError { code: index as u8 })
-            .into_diagnostic()
-            .wrap_err("Cannot convert constant index to u8")?;
-        self.write(OpCode::Constant.into(), line);
-        self.write(index, line);
         Ok(())
     }
 
-    /// Adds a constant to the chunk and returns the index of the constant.
+    /// Adds a constant to the chunk and returns the index of the constant. If an identical
+    /// constant was added before, its index is returned instead.
     pub fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.push(value);
-        self.constants.len() - 1
+        let constants = &mut self.constants;
+        *self
+            .constant_indices
+            .entry(ConstantKey::from(&value))
+            .or_insert_with(|| {
+                constants.push(value);
+                constants.len() - 1
+            })
+    }
+
+    /// Like `add_constant`, but fails rather than add more constants than a 24-bit operand
+    /// can index.
+    pub fn try_add_constant(&mut self, value: Value) -> Result<usize> {
+        if self.constants.len() >= MAX_CONSTANTS && self.find_constant(&value).is_none() {
+            return Err(CloxersError::ConstantsOverflowed).into_diagnostic();
+        }
+        Ok(self.add_constant(value))
     }
 
     pub fn disassemble(&self, name: &str) -> Result<String> {
         let mut output = String::new();
         writeln!(&mut output, "== {} ==", name)
             .map_err(|_| miette!("Cannot write disassembly header for {}", name))?;
-        for (idx, bytearray) in self.into_iter().enumerate() {
+        for (idx, (offset, bytearray)) in self.into_iter().enumerate() {
             // instruction index starts at 1 for disassembly
-            self.disassemble_instruction(&mut output, idx + 1, &bytearray)?;
+            self.disassemble_instruction(&mut output, idx + 1, offset, &bytearray)?;
         }
         Ok(output)
     }
@@ -81,21 +224,96 @@ impl Chunk {
         &self,
         output: &mut dyn Write,
         idx: usize,
-        bytearray: &[u8; 3],
+        offset: usize,
+        bytearray: &[u8; 4],
     ) -> Result<()> {
-        let [op_code_byte, op1_offset, _op2_offset] = bytearray;
-        write!(output, "{}. {:04} ", idx, op_code_byte)
-            .map_err(|_| miette!("Cannot write offset at 0"))?;
+        let [op_code_byte, op1_offset, op2_offset, op3_offset] = bytearray;
+        write!(output, "{}. {:04} ", idx, offset)
+            .map_err(|_| miette!("Cannot write offset at {}", offset))?;
+        let line = self.line_at(offset);
+        if offset > 0 && line == self.line_at(offset - 1) {
+            write!(output, "   | ")
+        } else {
+            write!(output, "{:>4} ", line.unwrap_or_default())
+        }
+        .map_err(|_| miette!("Cannot write line at {}", offset))?;
 
         match OpCode::try_from(*op_code_byte) {
             Ok(op_code) => match op_code {
                 OpCode::Return => self.simple_instruction(output, op_code.name()),
                 OpCode::Constant => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::ConstantLong => self.constant_long_instruction(
+                    output,
+                    op_code.name(),
+                    [*op1_offset, *op2_offset, *op3_offset],
+                ),
                 OpCode::Negate => self.simple_instruction(output, op_code.name()),
                 OpCode::Add => self.simple_instruction(output, op_code.name()),
                 OpCode::Subtract => self.simple_instruction(output, op_code.name()),
                 OpCode::Multiply => self.simple_instruction(output, op_code.name()),
                 OpCode::Divide => self.simple_instruction(output, op_code.name()),
+                OpCode::Nil => self.simple_instruction(output, op_code.name()),
+                OpCode::True => self.simple_instruction(output, op_code.name()),
+                OpCode::False => self.simple_instruction(output, op_code.name()),
+                OpCode::Not => self.simple_instruction(output, op_code.name()),
+                OpCode::Equal => self.simple_instruction(output, op_code.name()),
+                OpCode::Greater => self.simple_instruction(output, op_code.name()),
+                OpCode::Less => self.simple_instruction(output, op_code.name()),
+                OpCode::Print => self.simple_instruction(output, op_code.name()),
+                OpCode::Pop => self.simple_instruction(output, op_code.name()),
+                OpCode::DefineGlobal => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::GetGlobal => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::SetGlobal => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::GetLocal => self.byte_instruction(output, op_code.name(), op1_offset),
+                OpCode::SetLocal => self.byte_instruction(output, op_code.name(), op1_offset),
+                OpCode::Call => self.byte_instruction(output, op_code.name(), op1_offset),
+                OpCode::GetUpvalue => self.byte_instruction(output, op_code.name(), op1_offset),
+                OpCode::SetUpvalue => self.byte_instruction(output, op_code.name(), op1_offset),
+                OpCode::CloseUpvalue => self.simple_instruction(output, op_code.name()),
+                OpCode::Closure => {
+                    self.closure_instruction(output, op_code, offset, *op1_offset as usize)
+                }
+                OpCode::ClosureLong => {
+                    let [_, high, mid, low] = *bytearray;
+                    let index = u32::from_be_bytes([0, high, mid, low]) as usize;
+                    self.closure_instruction(output, op_code, offset, index)
+                }
+                OpCode::Jump | OpCode::JumpIfFalse => self.jump_instruction(
+                    output,
+                    op_code.name(),
+                    offset,
+                    true,
+                    [*op1_offset, *op2_offset],
+                ),
+                OpCode::Loop => self.jump_instruction(
+                    output,
+                    op_code.name(),
+                    offset,
+                    false,
+                    [*op1_offset, *op2_offset],
+                ),
+                OpCode::Class => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::GetProperty => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::SetProperty => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::Method => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::Inherit => self.simple_instruction(output, op_code.name()),
+                OpCode::ToString => self.simple_instruction(output, op_code.name()),
+                OpCode::GetSuper => self.arity1_instruction(output, op_code.name(), op1_offset),
+                OpCode::Invoke | OpCode::SuperInvoke => {
+                    self.invoke_instruction(output, op_code.name(), op1_offset, op2_offset)
+                }
+                OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::GetSuperLong => self.constant_long_instruction(
+                    output,
+                    op_code.name(),
+                    [*op1_offset, *op2_offset, *op3_offset],
+                ),
             },
             Err(e) => Err(CloxersError::OpCodeError {
                 code: *op_code_byte,
@@ -111,6 +329,98 @@ impl Chunk {
         writeln!(output, "{}", name).map_err(|_| miette!("Cannot write simple instruction"))
     }
 
+    /// Writes an instruction whose operand is used as-is (such as a stack slot) to the output.
+    pub fn byte_instruction(&self, output: &mut dyn Write, name: &str, operand: &u8) -> Result<()> {
+        writeln!(output, "{:<16}\t{}", name, operand)
+            .map_err(|_| miette!("Cannot write byte instruction"))
+    }
+
+    /// Writes a jump instruction along with the byte offset it jumps to.
+    pub fn jump_instruction(
+        &self,
+        output: &mut dyn Write,
+        name: &str,
+        offset: usize,
+        forward: bool,
+        operands: [u8; 2], // big-endian distance from the end of this instruction
+    ) -> Result<()> {
+        let jump = u16::from_be_bytes(operands) as usize;
+        let next = offset + 3;
+        let target = if forward {
+            next + jump
+        } else {
+            next.checked_sub(jump).ok_or_else(|| {
+                miette!(
+                    "Loop at {} jumps back before the start of the chunk",
+                    offset
+                )
+            })?
+        };
+        writeln!(output, "{:<16}\t{} -> {}", name, offset, target)
+            .map_err(|_| miette!("Cannot write jump instruction"))
+    }
+
+    /// Writes a closure instruction: its function constant, then one line per captured variable.
+    pub fn closure_instruction(
+        &self,
+        output: &mut dyn Write,
+        op_code: OpCode,
+        offset: usize,
+        constant: usize,
+    ) -> Result<()> {
+        self.indexed_instruction(output, op_code.name(), constant)?;
+        // the upvalues follow the constant's index, which is one or three bytes long
+        let mut operand = offset + 1 + op_code.operand_offset();
+        for _ in 0..self.upvalue_count(constant) {
+            let (is_local, index) = match self.code.get(operand..operand + 2) {
+                Some(&[is_local, index]) => (is_local, index),
+                _ => {
+                    return Err(CloxersError::BadInstruction(format!(
+                        "Missing upvalue operands at {}",
+                        operand
+                    )))
+                    .into_diagnostic()
+                }
+            };
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            writeln!(
+                output,
+                "{:04}      |                     {} {}",
+                operand, kind, index
+            )
+            .map_err(|_| miette!("Cannot write closure upvalue"))?;
+            operand += 2;
+        }
+        Ok(())
+    }
+
+    /// The number of upvalues captured by the function constant of a closure instruction.
+    fn upvalue_count(&self, constant: usize) -> usize {
+        self.read_constant(constant)
+            .and_then(|value| value.as_obj())
+            .and_then(|obj| obj.as_function().map(|f| f.upvalue_count))
+            .unwrap_or(0)
+    }
+
+    /// Writes an invoke instruction: the method name constant and the number of arguments.
+    pub fn invoke_instruction(
+        &self,
+        output: &mut dyn Write,
+        name: &str,
+        constant: &u8,
+        arg_count: &u8,
+    ) -> Result<()> {
+        let method = self.read_constant(*constant as usize).ok_or_else(|| {
+            CloxersError::BadInstruction(format!("Missing constant index {}", constant))
+        })?;
+        writeln!(
+            output,
+            "{:<16}\t({} args) {} => {}",
+            name, arg_count, constant, method
+        )
+        .map_err(|_| miette!("Cannot write invoke instruction"))
+    }
+
     /// Writes a constant instruction to the output.
     pub fn arity1_instruction(
         &self,
@@ -138,6 +448,27 @@ impl Chunk {
             })?;
         Ok(())
     }
+
+    /// Writes a constant instruction with a 24-bit index to the output.
+    pub fn constant_long_instruction(
+        &self,
+        output: &mut dyn Write,
+        name: &str,
+        operands: [u8; 3], // big-endian index in the constants vec
+    ) -> Result<()> {
+        let [high, mid, low] = operands;
+        let index = u32::from_be_bytes([0, high, mid, low]) as usize;
+        self.indexed_instruction(output, name, index)
+    }
+
+    /// Writes an instruction along with the constant at `index`.
+    fn indexed_instruction(&self, output: &mut dyn Write, name: &str, index: usize) -> Result<()> {
+        let value = self.read_constant(index).ok_or_else(|| {
+            CloxersError::BadInstruction(format!("Missing constant index {}", index))
+        })?;
+        writeln!(output, "{:<16}\t{} => {}", name, index, value)
+            .map_err(|_| miette!("Cannot write constant instruction"))
+    }
 }
 
 /// We will implement an iterator for the Chunk struct so we can iterate
@@ -145,30 +476,46 @@ impl Chunk {
 pub struct ChunkIter<'a> {
     // Program counter should always point to an OppCode u8.
     pc: usize,
-    code: &'a [u8],
+    chunk: &'a Chunk,
 }
 
 impl<'a> Iterator for ChunkIter<'a> {
-    type Item = [u8; 3]; // OpCode + up to 2 operands
+    type Item = (usize, [u8; 4]); // byte offset of the OpCode, OpCode + up to 3 operands
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.pc < self.code.len() {
-            let op = self.code[self.pc];
+        let code = &self.chunk.code;
+        if self.pc < code.len() {
+            let start = self.pc;
+            let op = code[self.pc];
             let op_code = OpCode::try_from(op).ok()?;
             let offset = op_code.operand_offset();
             self.pc += 1; // move to next byte
             match offset {
-                0 => Some([op, 0, 0]),
+                0 => Some((start, [op, 0, 0, 0])),
                 1 => {
-                    let operand = self.code[self.pc];
+                    let operand = *code.get(self.pc)?;
                     self.pc += 1; // advance beyond the operand
-                    Some([op, operand, 0])
+                    if let OpCode::Closure = op_code {
+                        // skip the (is_local, index) pair for each captured variable
+                        self.pc += 2 * self.chunk.upvalue_count(operand as usize);
+                    }
+                    Some((start, [op, operand, 0, 0]))
                 }
                 2 => {
-                    let operand1 = self.code[self.pc];
-                    let operand2 = self.code[self.pc + 1];
+                    let operand1 = *code.get(self.pc)?;
+                    let operand2 = *code.get(self.pc + 1)?;
                     self.pc += 2; // advance beyond the two operands
-                    Some([op, operand1, operand2])
+                    Some((start, [op, operand1, operand2, 0]))
+                }
+                3 => {
+                    let operands = code.get(self.pc..self.pc + 3)?;
+                    let [high, mid, low] = [operands[0], operands[1], operands[2]];
+                    self.pc += 3; // advance beyond the three operands
+                    if let OpCode::ClosureLong = op_code {
+                        let constant = u32::from_be_bytes([0, high, mid, low]) as usize;
+                        self.pc += 2 * self.chunk.upvalue_count(constant);
+                    }
+                    Some((start, [op, high, mid, low]))
                 }
                 _ => None,
             }
@@ -179,14 +526,11 @@ impl<'a> Iterator for ChunkIter<'a> {
 }
 
 impl<'a> IntoIterator for &'a Chunk {
-    type Item = [u8; 3];
+    type Item = (usize, [u8; 4]);
     type IntoIter = ChunkIter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        ChunkIter {
-            pc: 0,
-            code: &self.code,
-        }
+        ChunkIter { pc: 0, chunk: self }
     }
 }
 
@@ -205,18 +549,18 @@ mod tests {
         let mut chunk = Chunk::new();
         chunk.write(OpCode::Return.into(), 0);
         assert_eq!(chunk.code.len(), 1);
-        assert_eq!(chunk.code[0], OpCode::Return.try_into().unwrap());
+        assert_eq!(chunk.code[0], u8::from(OpCode::Return));
     }
 
     #[test]
     fn test_chunk_disassemble() {
         let mut chunk = Chunk::new();
         chunk.write(OpCode::Return.into(), 5);
-        let _ = chunk.write_constant(Value::Number(1.2), 1);
-        let _ = chunk.write_constant(Value::Number(-5.0), 1);
-        let _ = chunk.write(OpCode::Add.into(), 2);
-        let _ = chunk.write(OpCode::Subtract.into(), 3);
-        let _ = chunk.write(OpCode::Multiply.into(), 4);
+        let _ = chunk.write_constant(Value::number(1.2), 1);
+        let _ = chunk.write_constant(Value::number(-5.0), 1);
+        chunk.write(OpCode::Add.into(), 2);
+        chunk.write(OpCode::Subtract.into(), 3);
+        chunk.write(OpCode::Multiply.into(), 4);
         let result = chunk.disassemble("test");
         println!("{:?}", result);
         assert!(result.is_ok());
@@ -225,14 +569,158 @@ mod tests {
 
         let expected = concat!(
             "== test ==\n",
-            "1. 0000 OP_RETURN\n",
-            "2. 0006 OP_CONSTANT     	0 => 1.2\n",
-            "3. 0006 OP_CONSTANT     	1 => -5\n",
-            "4. 0002 OP_ADD\n",
-            "5. 0003 OP_SUBTRACT\n",
-            "6. 0004 OP_MULTIPLY\n",
+            "1. 0000    5 OP_RETURN\n",
+            "2. 0001    1 OP_CONSTANT     	0 => 1.2\n",
+            "3. 0003    | OP_CONSTANT     	1 => -5\n",
+            "4. 0005    2 OP_ADD\n",
+            "5. 0006    3 OP_SUBTRACT\n",
+            "6. 0007    4 OP_MULTIPLY\n",
         );
         println!("{}", result);
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn test_line_table() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.line_at(0), None);
+        for _ in 0..1000 {
+            chunk.write(OpCode::Nil.into(), 1);
+        }
+        chunk.write(OpCode::Pop.into(), 3);
+        chunk.write(OpCode::Pop.into(), 3);
+        chunk.write(OpCode::Return.into(), 1);
+        // one run per change of line, however long the run
+        assert_eq!(chunk.lines.len(), 3);
+        assert_eq!(chunk.line_at(0), Some(1));
+        assert_eq!(chunk.line_at(999), Some(1));
+        assert_eq!(chunk.line_at(1000), Some(3));
+        assert_eq!(chunk.line_at(1001), Some(3));
+        assert_eq!(chunk.line_at(1002), Some(1));
+        assert_eq!(chunk.line_at(1003), None);
+    }
+
+    #[test]
+    fn test_span_table() {
+        let mut chunk = Chunk::new();
+        // hand-assembled bytecode has no spans
+        chunk.write(OpCode::Nil.into(), 1);
+        assert_eq!(chunk.span_at(0), None);
+
+        chunk.write_span((3, 2).into());
+        chunk.write(OpCode::GetGlobal.into(), 1);
+        chunk.write(0, 1);
+        chunk.write(OpCode::Pop.into(), 1);
+        chunk.write_span((7, 1).into());
+        chunk.write(OpCode::Negate.into(), 2);
+        // only instructions given a span have one, covering their operands
+        assert_eq!(chunk.spans.len(), 2);
+        assert_eq!(chunk.span_at(0), None);
+        assert_eq!(chunk.span_at(1), Some((3, 2).into()));
+        assert_eq!(chunk.span_at(2), Some((3, 2).into()));
+        assert_eq!(chunk.span_at(3), None);
+        assert_eq!(chunk.span_at(4), Some((7, 1).into()));
+        assert_eq!(chunk.span_at(5), None);
+        // spans don't break up the runs of lines
+        assert_eq!(chunk.lines.len(), 2);
+    }
+
+    #[test]
+    fn test_constants_are_deduplicated() {
+        let mut heap = crate::memory::Heap::new();
+        let hello = Value::obj(heap.copy_string("hello", &()));
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.find_constant(&Value::number(1.0)), None);
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 0);
+        assert_eq!(chunk.add_constant(hello), 1);
+        assert_eq!(chunk.add_constant(Value::number(1.0)), 0);
+        assert_eq!(
+            chunk.add_constant(Value::obj(heap.copy_string("hello", &()))),
+            1
+        );
+        assert_eq!(chunk.find_constant(&hello), Some(1));
+        assert_eq!(chunk.constants().len(), 2);
+
+        // The same constant instruction is written each time, sharing one slot.
+        chunk.write_constant(Value::number(1.0), 1).unwrap();
+        chunk.write_constant(Value::number(1.0), 1).unwrap();
+        assert_eq!(
+            chunk.code(),
+            &[u8::from(OpCode::Constant), 0, u8::from(OpCode::Constant), 0]
+        );
+        assert_eq!(chunk.constants().len(), 2);
+    }
+
+    #[test]
+    fn test_constant_identity() {
+        let mut chunk = Chunk::new();
+        // 0.0 == -0.0, but they print differently and divide differently.
+        let zero = chunk.add_constant(Value::number(0.0));
+        let negative_zero = chunk.add_constant(Value::number(-0.0));
+        assert_ne!(zero, negative_zero);
+        assert_eq!(
+            chunk.find_constant(&Value::number(-0.0)),
+            Some(negative_zero)
+        );
+        // NaN != NaN, but one NaN constant serves for all.
+        let nan = chunk.add_constant(Value::number(f64::NAN));
+        assert_eq!(chunk.add_constant(Value::number(-f64::NAN)), nan);
+        // Values of different types are never the same constant.
+        let falsey = chunk.add_constant(Value::bool(false));
+        let nil = chunk.add_constant(Value::nil());
+        assert_ne!(falsey, nil);
+        assert_eq!(chunk.add_constant(Value::nil()), nil);
+        assert_eq!(chunk.add_constant(Value::bool(true)), 5);
+        assert_eq!(chunk.constants().len(), 6);
+    }
+
+    #[test]
+    fn test_write_constant_long() {
+        let mut chunk = Chunk::new();
+        for i in 0..256 {
+            chunk.write_constant(Value::number(i as f64), 1).unwrap();
+        }
+        // Exactly 256 constants fit in a single-byte operand...
+        assert_eq!(chunk.len(), 2 * 256);
+        assert_eq!(chunk.code[2 * 255], u8::from(OpCode::Constant));
+        assert_eq!(chunk.code[2 * 255 + 1], 255);
+        // ...and the rest switch to a 24-bit one.
+        chunk.write_constant(Value::number(256.0), 2).unwrap();
+        chunk.write_constant(Value::number(257.0), 2).unwrap();
+        chunk.write(OpCode::Add.into(), 2);
+        assert_eq!(
+            &chunk.code[2 * 256..],
+            &[
+                u8::from(OpCode::ConstantLong),
+                0,
+                1,
+                0,
+                u8::from(OpCode::ConstantLong),
+                0,
+                1,
+                1,
+                u8::from(OpCode::Add),
+            ]
+        );
+
+        let instructions: Vec<_> = chunk.into_iter().skip(255).collect();
+        assert_eq!(
+            instructions,
+            vec![
+                (510, [u8::from(OpCode::Constant), 255, 0, 0]),
+                (512, [u8::from(OpCode::ConstantLong), 0, 1, 0]),
+                (516, [u8::from(OpCode::ConstantLong), 0, 1, 1]),
+                (520, [u8::from(OpCode::Add), 0, 0, 0]),
+            ]
+        );
+
+        let result = chunk.disassemble("test").unwrap();
+        let expected = concat!(
+            "256. 0510    | OP_CONSTANT     	255 => 255\n",
+            "257. 0512    2 OP_CONSTANT_LONG	256 => 256\n",
+            "258. 0516    | OP_CONSTANT_LONG	257 => 257\n",
+            "259. 0520    | OP_ADD\n",
+        );
+        assert!(result.ends_with(expected), "{}", result);
+    }
 }
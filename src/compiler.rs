@@ -0,0 +1,1844 @@
+use std::borrow::Cow;
+
+use miette::SourceSpan;
+
+use crate::chunk::Chunk;
+use crate::error::{Diagnostics, InterpreterError, SourceDiagnostic};
+use crate::memory::{Heap, Roots};
+use crate::object::{ObjFunction, ObjKind, ObjRef};
+use crate::opcodes::OpCode;
+use crate::token::{Token, TokenType};
+use crate::value::Value;
+
+/// Operator precedence from lowest to highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    /// The next-highest precedence level: used to make binary operators left-associative.
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+/// Parse functions are told whether they may consume a trailing `=`, which is only the case
+/// when the surrounding expression binds no tighter than assignment.
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+/// A row in the Pratt parser table.
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// Locals live in stack slots, so there can be no more than a one-byte operand can address.
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+
+/// A one-byte operand counts arguments, so that is also the limit on parameters.
+const MAX_ARITY: usize = u8::MAX as usize;
+
+/// Upvalues are also addressed by a one-byte operand.
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+/// A local variable in scope at the current point of compilation.
+#[derive(Debug)]
+struct Local<'a> {
+    name: Cow<'a, str>,
+    // `None` while the variable's initializer is still being compiled
+    depth: Option<usize>,
+    // whether a closure refers to the variable, so it must outlive its stack slot
+    is_captured: bool,
+}
+
+/// A variable from an enclosing function which the current function refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Upvalue {
+    // a stack slot of the immediately enclosing function if `is_local`,
+    // otherwise one of that function's own upvalues
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Per-class compilation state, used to check uses of `this` and `super`.
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Per-function compilation state: each function declaration pushes a new one of these
+/// so that nested functions get their own chunk and locals.
+struct FunctionState<'a> {
+    function: ObjFunction,
+    kind: FunctionKind,
+    // Locals in declaration order: the index of each is its stack slot.
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionState<'_> {
+    fn new(kind: FunctionKind, name: Option<ObjRef>) -> Self {
+        Self {
+            function: ObjFunction::new(name),
+            kind,
+            // Slot zero holds the receiver in methods, where it is reachable as `this`. Otherwise
+            // it holds the function being called: give it a name no identifier can have.
+            locals: vec![Local {
+                name: match kind {
+                    FunctionKind::Initializer | FunctionKind::Method => "this",
+                    FunctionKind::Function | FunctionKind::Script => "",
+                }
+                .into(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Functions still being compiled are not on the heap yet, so the objects in their constant
+/// pools are only reachable from the compiler.
+impl Roots for Vec<FunctionState<'_>> {
+    fn mark_roots(&self, heap: &mut Heap) {
+        for state in self {
+            if let Some(name) = state.function.name {
+                heap.mark_object(name);
+            }
+            for constant in state.function.chunk.constants() {
+                heap.mark_value(constant);
+            }
+        }
+    }
+}
+
+/// The tokens of a program, as scanned on demand by a `Scanner`.
+type Tokens<'a> = Box<dyn Iterator<Item = Result<Token<'a>, InterpreterError>> + 'a>;
+
+/// Single-pass compiler: parses tokens with a Pratt parser and emits bytecode as it goes.
+pub struct Compiler<'a> {
+    // pulled one at a time, so only the two below are kept
+    tokens: Tokens<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    heap: &'a mut Heap,
+    // whatever else refers to objects on `heap`, which must survive collections during compilation
+    roots: &'a dyn Roots,
+    // The function being compiled is last; the functions enclosing it precede it.
+    states: Vec<FunctionState<'a>>,
+    // The innermost class declaration enclosing the code being compiled is last.
+    classes: Vec<ClassState>,
+    // every error found so far: the code compiled is only used if there are none
+    errors: Vec<SourceDiagnostic>,
+    // Set by an error until the parser reaches the next statement. Parsing carries on meanwhile,
+    // but any errors are likely caused by the first one, so they are not recorded.
+    panic_mode: bool,
+    // source the scanner could not make tokens of, reported instead of any other errors
+    scan_errors: Vec<SourceDiagnostic>,
+}
+
+/// Compiles a stream of tokens (such as a `Scanner`) into a function holding the top-level
+/// code. Objects are allocated on `heap`, which must outlive the function.
+///
+/// The heap may be collected during compilation: `roots` must mark every object on it which is
+/// in use elsewhere, such as by the VM.
+pub(crate) fn compile<'a>(
+    tokens: impl Iterator<Item = Result<Token<'a>, InterpreterError>> + 'a,
+    heap: &'a mut Heap,
+    roots: &'a dyn Roots,
+) -> Result<ObjRef, InterpreterError> {
+    Compiler::new(tokens, heap, roots).compile()
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(
+        tokens: impl Iterator<Item = Result<Token<'a>, InterpreterError>> + 'a,
+        heap: &'a mut Heap,
+        roots: &'a dyn Roots,
+    ) -> Self {
+        // Stands in until the first token is read, and for the end if there are no tokens at all.
+        let end = Token::end(1, 1, 0);
+        let mut compiler = Self {
+            tokens: Box::new(tokens),
+            current: end.clone(),
+            previous: end,
+            heap,
+            roots,
+            states: vec![FunctionState::new(FunctionKind::Script, None)],
+            classes: Vec::new(),
+            errors: Vec::new(),
+            panic_mode: false,
+            scan_errors: Vec::new(),
+        };
+        compiler.advance();
+        compiler
+    }
+
+    /// Compiles every declaration, carrying on past errors so that all of them are reported.
+    pub(crate) fn compile(mut self) -> Result<ObjRef, InterpreterError> {
+        while !self.token_match(TokenType::Eof) {
+            self.declaration();
+        }
+        let (function, _) = self.end_compiler();
+        if !self.scan_errors.is_empty() {
+            // Compile errors could be caused by the missing tokens, so only report these.
+            Err(InterpreterError::ScannerError(Diagnostics::new(
+                "Could not scan the source",
+                self.scan_errors,
+            )))
+        } else if !self.errors.is_empty() {
+            Err(InterpreterError::CompileError(Diagnostics::new(
+                "Could not compile the source",
+                self.errors,
+            )))
+        } else {
+            Ok(function)
+        }
+    }
+
+    fn state(&self) -> &FunctionState<'a> {
+        self.states
+            .last()
+            .expect("there is always a function being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.states
+            .last_mut()
+            .expect("there is always a function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    /// Finishes the current function and moves it onto the heap. Also returns the variables
+    /// it captures, which the enclosing function needs to emit the closure.
+    fn end_compiler(&mut self) -> (ObjRef, Vec<Upvalue>) {
+        self.emit_return();
+        let mut state = self
+            .states
+            .pop()
+            .expect("there is always a function being compiled");
+        state.function.upvalue_count = state.upvalues.len();
+        // The function is no longer on `states`, but what it refers to is kept by the allocation.
+        let roots: [&dyn Roots; 2] = [self.roots, &self.states];
+        let function = self.heap.alloc(ObjKind::Function(state.function), &roots);
+        (function, state.upvalues)
+    }
+
+    fn rule(token_type: &TokenType) -> ParseRule<'a> {
+        match token_type {
+            TokenType::LeftParen => {
+                ParseRule::new(Some(Self::grouping), Some(Self::call), Precedence::Call)
+            }
+            TokenType::Dot => ParseRule::new(None, Some(Self::dot), Precedence::Call),
+            TokenType::Minus => {
+                ParseRule::new(Some(Self::unary), Some(Self::binary), Precedence::Term)
+            }
+            TokenType::Plus => ParseRule::new(None, Some(Self::binary), Precedence::Term),
+            TokenType::Slash | TokenType::Star => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Factor)
+            }
+            TokenType::Bang => ParseRule::new(Some(Self::unary), None, Precedence::None),
+            TokenType::BangEqual | TokenType::EqualEqual => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Equality)
+            }
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => {
+                ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
+            }
+            TokenType::Identifier => ParseRule::new(Some(Self::variable), None, Precedence::None),
+            TokenType::String => ParseRule::new(Some(Self::string), None, Precedence::None),
+            TokenType::InterpolationStart => {
+                ParseRule::new(Some(Self::interpolation), None, Precedence::None)
+            }
+            TokenType::Number => ParseRule::new(Some(Self::number), None, Precedence::None),
+            TokenType::And => ParseRule::new(None, Some(Self::and), Precedence::And),
+            TokenType::Or => ParseRule::new(None, Some(Self::or), Precedence::Or),
+            TokenType::Super => ParseRule::new(Some(Self::super_), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(Self::this), None, Precedence::None),
+            TokenType::False | TokenType::Nil | TokenType::True => {
+                ParseRule::new(Some(Self::literal), None, Precedence::None)
+            }
+            _ => ParseRule::new(None, None, Precedence::None),
+        }
+    }
+
+    // Token handling
+
+    /// Moves on to the next token, skipping past any source which could not be scanned. Once the
+    /// tokens run out, the last one (the scanner's `Eof`) stays current.
+    ///
+    /// Other errors can only come from token streams other than a `Scanner`. They are recorded
+    /// against the current token, so compilation fails but carries on like it does for its own
+    /// errors.
+    fn advance(&mut self) {
+        loop {
+            match self.tokens.next() {
+                Some(Ok(token)) => {
+                    self.previous = std::mem::replace(&mut self.current, token);
+                    return;
+                }
+                Some(Err(InterpreterError::ScannerError(diagnostics))) => {
+                    self.scan_errors.extend(diagnostics.errors)
+                }
+                Some(Err(InterpreterError::CompileError(diagnostics))) => {
+                    self.errors.extend(diagnostics.errors)
+                }
+                Some(Err(error @ InterpreterError::RuntimeError)) => {
+                    let diagnostic = Self::diagnostic(&self.current, &error.to_string());
+                    self.errors.push(diagnostic);
+                }
+                None => {
+                    self.previous = self.current.clone();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn token_match(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    /// Consumes the current token if it is a `token_type`. Otherwise reports an error and carries
+    /// on as if it had been there.
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.check(token_type) {
+            self.advance();
+            return;
+        }
+        self.error_at_current(message);
+    }
+
+    /// Skips tokens until the end of the current statement or the start of the next, where
+    /// parsing can resume after an error without reporting errors which only follow from it.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(TokenType::Eof) {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    // Errors
+
+    fn diagnostic(token: &Token, message: &str) -> SourceDiagnostic {
+        let location = match token.token_type {
+            TokenType::Eof => "at end".to_string(),
+            _ => format!("at '{}'", Self::lexeme(token)),
+        };
+        SourceDiagnostic::new(message, location, token.span)
+    }
+
+    /// Records an error, unless one was already recorded for the current statement.
+    fn report(&mut self, diagnostic: SourceDiagnostic) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.errors.push(diagnostic);
+    }
+
+    /// Reports an error at the token just consumed.
+    fn error(&mut self, message: &str) {
+        let diagnostic = Self::diagnostic(&self.previous, message);
+        self.report(diagnostic);
+    }
+
+    /// Reports an error at the token about to be consumed.
+    fn error_at_current(&mut self, message: &str) {
+        let diagnostic = Self::diagnostic(&self.current, message);
+        self.report(diagnostic);
+    }
+
+    // Bytecode emission
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous.line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    /// Emits an opcode which runtime errors should blame on the last token parsed.
+    fn emit_op(&mut self, op_code: OpCode) {
+        let Token { line, span, .. } = self.previous;
+        self.write_op(op_code, line, span);
+    }
+
+    /// Emits an opcode which runtime errors should blame on `token` rather than the last one
+    /// parsed, such as an operator whose operands have been compiled since.
+    fn emit_op_at(&mut self, op_code: OpCode, token: &Token) {
+        self.write_op(op_code, token.line, token.span);
+    }
+
+    fn emit_ops_at(&mut self, op_code1: OpCode, op_code2: OpCode, token: &Token) {
+        self.emit_op_at(op_code1, token);
+        self.emit_op_at(op_code2, token);
+    }
+
+    fn write_op(&mut self, op_code: OpCode, line: usize, span: SourceSpan) {
+        // Most instructions can't fail, so only those which can keep their span.
+        if op_code.can_fail() {
+            self.chunk().write_span(span);
+        }
+        self.chunk().write(op_code.into(), line);
+    }
+
+    /// Functions without an explicit `return` return nil, except initializers, which return `this`.
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_bytes(OpCode::GetLocal.into(), 0);
+        } else {
+            self.emit_byte(OpCode::Nil.into());
+        }
+        self.emit_byte(OpCode::Return.into());
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let line = self.previous.line;
+        if self.chunk().write_constant(value, line).is_err() {
+            self.error("Too many constants in one chunk.");
+        }
+    }
+
+    /// Emits a jump instruction with a placeholder operand and returns the operand's offset,
+    /// to be filled in by `patch_jump` once the jump target is known.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction.into());
+        self.emit_bytes(0xff, 0xff);
+        self.chunk().len() - 2
+    }
+
+    /// Points the jump whose operand is at `offset` to the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump operand itself
+        let Ok(jump) = u16::try_from(self.chunk().len() - offset - 2) else {
+            self.error("Too much code to jump over.");
+            return;
+        };
+        let [high, low] = jump.to_be_bytes();
+        self.chunk().patch(offset, high);
+        self.chunk().patch(offset + 1, low);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop.into());
+        // +2 to jump back over the operand too
+        let jump = u16::try_from(self.chunk().len() - loop_start + 2).unwrap_or_else(|_| {
+            self.error("Loop body too large.");
+            u16::MAX
+        });
+        let [high, low] = jump.to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    /// Adds `value` to the constant pool and returns its index.
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.chunk().try_add_constant(value).unwrap_or_else(|_| {
+            self.error("Too many constants in one chunk.");
+            0
+        })
+    }
+
+    /// Emits an instruction with an operand indexing the constant pool: `op_code` with a one-byte
+    /// operand if `index` fits in one, otherwise its long form with a 24-bit operand.
+    ///
+    /// Stack slots and upvalues are also addressed by this operand, but never need the long form.
+    fn emit_indexed(&mut self, op_code: OpCode, index: usize) {
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_op(op_code);
+            self.emit_byte(index);
+            return;
+        }
+        let long = op_code
+            .long()
+            .unwrap_or_else(|| unreachable!("{} has no long form", op_code));
+        // `make_constant` keeps the index within the 24 bits of the operand.
+        let [_, high, mid, low] = (index as u32).to_be_bytes();
+        self.emit_op(long);
+        self.emit_bytes(high, mid);
+        self.emit_byte(low);
+    }
+
+    // Declarations and statements
+
+    /// Collects garbage if the heap asks for it. Only called between declarations, where every
+    /// object the compiler has allocated is in some function's constant pool.
+    fn collect_garbage(&mut self) {
+        if !self.heap.should_collect() {
+            return;
+        }
+        let roots: [&dyn Roots; 2] = [self.roots, &self.states];
+        self.heap.collect(&roots);
+    }
+
+    /// Compiles a declaration. After an error, skips ahead to the next statement of the same
+    /// block, and carries on from there.
+    fn declaration(&mut self) {
+        self.collect_garbage();
+        if self.token_match(TokenType::Class) {
+            self.class_declaration();
+        } else if self.token_match(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.token_match(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        self.class_body(&class_name);
+        self.classes.pop();
+    }
+
+    /// Compiles the superclass clause and methods of a class, binding each method to the class
+    /// named `class_name`.
+    fn class_body(&mut self, class_name: &Token) {
+        if self.token_match(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if Self::lexeme(class_name) == Self::lexeme(&self.previous) {
+                self.error("A class can't inherit from itself.");
+            }
+            // Methods reach the superclass through a local named `super`, which closures over
+            // them capture as an upvalue. The new scope keeps each class's `super` separate.
+            self.begin_scope();
+            self.add_local("super".into());
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_op(OpCode::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Load the class so `OpCode::Method` can find it beneath each method's closure.
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop.into());
+
+        if self
+            .classes
+            .last()
+            .is_some_and(|class| class.has_superclass)
+        {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        let kind = if Self::lexeme(&name) == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind);
+        self.emit_indexed(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so its name is usable before the body is compiled.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    /// Compiles a function's parameters and body, leaving a closure over it on the stack.
+    fn function(&mut self, kind: FunctionKind) {
+        let roots: [&dyn Roots; 2] = [self.roots, &self.states];
+        let name = Self::lexeme(&self.previous);
+        let name = self.heap.copy_string(name, &roots);
+        self.states.push(FunctionState::new(kind, Some(name)));
+        // No matching end_scope: the whole state is discarded at the end of the function.
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > MAX_ARITY {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.token_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(Value::obj(function));
+        self.emit_indexed(OpCode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local.into(), upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+        if self.token_match(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil.into());
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.token_match(TokenType::Print) {
+            self.print_statement();
+        } else if self.token_match(TokenType::Return) {
+            self.return_statement();
+        } else if self.token_match(TokenType::For) {
+            self.for_statement();
+        } else if self.token_match(TokenType::If) {
+            self.if_statement();
+        } else if self.token_match(TokenType::While) {
+            self.while_statement();
+        } else if self.token_match(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print.into());
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop.into());
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().kind == FunctionKind::Script {
+            self.error("Can't return from top-level code.");
+        }
+        if self.token_match(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return.into());
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        // Both branches pop the condition, so it never outlives the statement.
+        self.emit_byte(OpCode::Pop.into());
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop.into());
+        if self.token_match(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+    }
+
+    /// Desugars `for (initializer; condition; increment) body` into jumps around a while-style loop.
+    fn for_statement(&mut self) {
+        // Variables declared in the initializer are scoped to the loop.
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.token_match(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.token_match(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk().len();
+        let mut exit_jump = None;
+        if !self.token_match(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop.into());
+        }
+
+        if !self.token_match(TokenType::RightParen) {
+            // The increment is compiled before the body but runs after it:
+            // jump over it now, and have the body loop back to it.
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk().len();
+            self.expression();
+            self.emit_byte(OpCode::Pop.into());
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop.into());
+        }
+        self.end_scope();
+    }
+
+    // Scopes
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        let scope_depth = self.state().scope_depth;
+        while self
+            .state()
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_some_and(|depth| depth > scope_depth))
+        {
+            let captured = self
+                .state_mut()
+                .locals
+                .pop()
+                .is_some_and(|local| local.is_captured);
+            // Captured variables move to the heap rather than being discarded.
+            if captured {
+                self.emit_byte(OpCode::CloseUpvalue.into());
+            } else {
+                self.emit_byte(OpCode::Pop.into());
+            }
+        }
+    }
+
+    // Variables
+
+    /// Consumes an identifier and declares it. For globals, returns the index of its name
+    /// in the constant pool; locals are addressed by stack slot so this is unused.
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+        let name = self.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let roots: [&dyn Roots; 2] = [self.roots, &self.states];
+        let name = self.heap.copy_string(Self::lexeme(name), &roots);
+        self.make_constant(Value::obj(name))
+    }
+
+    fn lexeme<'t>(token: &'t Token) -> &'t str {
+        token.lexeme.as_deref().unwrap_or_default()
+    }
+
+    /// Records a local variable in the current scope. Globals are late bound, so need no declaring.
+    fn declare_variable(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let name = Self::lexeme(&self.previous);
+        let declared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+            .any(|local| name == local.name);
+        if declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+        let name = self.previous.lexeme.clone().unwrap_or_default();
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Cow<'a, str>) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Marks the most recent local as ready for use, now that its initializer has been compiled.
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            // The initializer's value is already sitting in the local's stack slot.
+            self.mark_initialized();
+            return;
+        }
+        self.emit_indexed(OpCode::DefineGlobal, global);
+    }
+
+    /// Returns the stack slot of the innermost local called `name` in the function compiled by
+    /// `states[state]`, if there is one.
+    fn resolve_local(&mut self, state: usize, name: &Token) -> Option<u8> {
+        let name = Self::lexeme(name);
+        let (slot, local) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| name == local.name)?;
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        // `add_local` keeps the number of locals within `MAX_LOCALS`.
+        Some(slot as u8)
+    }
+
+    /// Returns the index of the upvalue through which the function compiled by `states[state]`
+    /// reaches the variable `name` in an enclosing function, adding upvalues along the way.
+    fn resolve_upvalue(&mut self, state: usize, name: &Token) -> Option<u8> {
+        if state == 0 {
+            // Top-level code has no enclosing function: `name` must be a global.
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(state, slot, true));
+        }
+        let index = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, index, false))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.states[state].upvalues;
+        // A function referring to the same variable twice shares one upvalue.
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        upvalues.push(upvalue);
+        // Checked against MAX_UPVALUES above.
+        (upvalues.len() - 1) as u8
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let state = self.states.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(state, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot.into())
+        } else if let Some(index) = self.resolve_upvalue(state, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index.into())
+        } else {
+            (
+                OpCode::GetGlobal,
+                OpCode::SetGlobal,
+                self.identifier_constant(name),
+            )
+        };
+        if can_assign && self.token_match(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed(set_op, arg);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    // Expressions
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let Some(prefix) = Self::rule(&self.previous.token_type).prefix else {
+            self.error("Expect expression.");
+            return;
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, can_assign);
+
+        while precedence <= Self::rule(&self.current.token_type).precedence {
+            self.advance();
+            if let Some(infix) = Self::rule(&self.previous.token_type).infix {
+                infix(self, can_assign);
+            }
+        }
+        // Nothing consumed the `=`, so the left-hand side was not something we can assign to.
+        if can_assign && self.token_match(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let value = self
+            .previous
+            .lexeme
+            .as_deref()
+            .and_then(|lexeme| lexeme.parse::<f64>().ok());
+        match value {
+            Some(value) => self.emit_constant(Value::number(value)),
+            None => self.error("Invalid number literal."),
+        }
+    }
+
+    /// `a and b`: if `a` is falsey it is the result, so skip `b` and leave `a` on the stack.
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop.into());
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    /// `a or b`: if `a` is truthy it is the result, so jump over `b`.
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // The scanner has already stripped the surrounding quotes.
+        let chars = self.previous.lexeme.as_deref().unwrap_or_default();
+        let roots: [&dyn Roots; 2] = [self.roots, &self.states];
+        let string = self.heap.copy_string(chars, &roots);
+        self.emit_constant(Value::obj(string));
+    }
+
+    /// `"start ${a} segment ${b} end"` compiles like `"start " + str(a) + " segment " + ...`,
+    /// where `str` leaves strings alone and converts anything else to one.
+    fn interpolation(&mut self, _can_assign: bool) {
+        // The start is always there, even if empty, so that the first `+` joins strings.
+        self.string(false);
+        loop {
+            self.expression();
+            self.emit_byte(OpCode::ToString.into());
+            self.emit_byte(OpCode::Add.into());
+            let more = self.token_match(TokenType::InterpolationSegment);
+            if !more {
+                self.consume(
+                    TokenType::InterpolationEnd,
+                    "Expect '}' after interpolated expression.",
+                );
+            }
+            if !Self::lexeme(&self.previous).is_empty() {
+                self.string(false);
+                self.emit_byte(OpCode::Add.into());
+            }
+            if !more {
+                return;
+            }
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous.token_type {
+            TokenType::False => self.emit_byte(OpCode::False.into()),
+            TokenType::Nil => self.emit_byte(OpCode::Nil.into()),
+            TokenType::True => self.emit_byte(OpCode::True.into()),
+            _ => unreachable!(
+                "literal() called for non-literal {}",
+                self.previous.token_type
+            ),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_op(OpCode::Call);
+        self.emit_byte(arg_count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(&self.previous.clone());
+        if can_assign && self.token_match(TokenType::Equal) {
+            self.expression();
+            self.emit_indexed(OpCode::SetProperty, name);
+        } else if self.token_match(TokenType::LeftParen) {
+            match u8::try_from(name) {
+                // `obj.method(args)`: call the method directly rather than via a bound method.
+                Ok(name) => {
+                    let arg_count = self.argument_list();
+                    self.emit_op(OpCode::Invoke);
+                    self.emit_bytes(name, arg_count);
+                }
+                // `Invoke` has no long form: bind the method and call that instead.
+                Err(_) => {
+                    self.emit_indexed(OpCode::GetProperty, name);
+                    self.call(false);
+                }
+            }
+        } else {
+            self.emit_indexed(OpCode::GetProperty, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // `this` is never assignable.
+        self.variable(false);
+    }
+
+    /// `super.method`, either called straight away or bound to `this` for later.
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(&self.previous.clone());
+        let Token {
+            line, column, span, ..
+        } = self.previous;
+        let this = Token::new(TokenType::This, Some("this".into()), line, column, span);
+        let super_ = Token::new(TokenType::Super, Some("super".into()), line, column, span);
+        self.named_variable(&this, false);
+        match u8::try_from(name) {
+            Ok(name) if self.token_match(TokenType::LeftParen) => {
+                let arg_count = self.argument_list();
+                self.named_variable(&super_, false);
+                self.emit_op(OpCode::SuperInvoke);
+                self.emit_bytes(name, arg_count);
+            }
+            // `SuperInvoke` has no long form: any call then goes through the bound method.
+            _ => {
+                self.named_variable(&super_, false);
+                self.emit_indexed(OpCode::GetSuper, name);
+            }
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == MAX_ARITY {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+                if !self.token_match(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        // Kept within MAX_ARITY above.
+        arg_count as u8
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        // Compile the operand first so it is on the stack when the operator runs.
+        self.parse_precedence(Precedence::Unary);
+        match operator.token_type {
+            TokenType::Minus => self.emit_op_at(OpCode::Negate, &operator),
+            TokenType::Bang => self.emit_op_at(OpCode::Not, &operator),
+            _ => unreachable!("unary() called for non-unary operator {}", operator),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        let rule = Self::rule(&operator.token_type);
+        self.parse_precedence(rule.precedence.next());
+        // Runtime errors point at the operator rather than at the end of the right operand.
+        match operator.token_type {
+            TokenType::Plus => self.emit_op_at(OpCode::Add, &operator),
+            TokenType::Minus => self.emit_op_at(OpCode::Subtract, &operator),
+            TokenType::Star => self.emit_op_at(OpCode::Multiply, &operator),
+            TokenType::Slash => self.emit_op_at(OpCode::Divide, &operator),
+            // `a != b`, `a >= b` and `a <= b` are desugared as `!(a == b)`, `!(a < b)` and `!(a > b)`
+            TokenType::BangEqual => self.emit_ops_at(OpCode::Equal, OpCode::Not, &operator),
+            TokenType::EqualEqual => self.emit_op_at(OpCode::Equal, &operator),
+            TokenType::Greater => self.emit_op_at(OpCode::Greater, &operator),
+            TokenType::GreaterEqual => self.emit_ops_at(OpCode::Less, OpCode::Not, &operator),
+            TokenType::Less => self.emit_op_at(OpCode::Less, &operator),
+            TokenType::LessEqual => self.emit_ops_at(OpCode::Greater, OpCode::Not, &operator),
+            _ => unreachable!("binary() called for non-binary operator {}", operator),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    /// Compiles `source` and returns the chunk of the top-level script function.
+    fn compile_source(source: &str) -> Result<(Chunk, Heap), InterpreterError> {
+        let mut heap = Heap::new();
+        let function = compile(Scanner::new(source), &mut heap, &())?;
+        let chunk = function.as_function().unwrap().chunk.clone();
+        Ok((chunk, heap))
+    }
+
+    #[test]
+    fn test_compile_precedence() {
+        let (chunk, _heap) = compile_source("1 + 2 * 3;").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => 1\n",
+            "2. 0002    | OP_CONSTANT     \t1 => 2\n",
+            "3. 0004    | OP_CONSTANT     \t2 => 3\n",
+            "4. 0006    | OP_MULTIPLY\n",
+            "5. 0007    | OP_ADD\n",
+            "6. 0008    | OP_POP\n",
+            "7. 0009    | OP_NIL\n",
+            "8. 0010    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_grouping_and_unary() {
+        let (chunk, _heap) = compile_source("-(1 - 2) / 4;").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => 1\n",
+            "2. 0002    | OP_CONSTANT     \t1 => 2\n",
+            "3. 0004    | OP_SUBTRACT\n",
+            "4. 0005    | OP_NEGATE\n",
+            "5. 0006    | OP_CONSTANT     \t2 => 4\n",
+            "6. 0008    | OP_DIVIDE\n",
+            "7. 0009    | OP_POP\n",
+            "8. 0010    | OP_NIL\n",
+            "9. 0011    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_comparison_desugaring() {
+        let (chunk, _heap) = compile_source("!(1 >= 2) != nil;").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => 1\n",
+            "2. 0002    | OP_CONSTANT     \t1 => 2\n",
+            "3. 0004    | OP_LESS\n",
+            "4. 0005    | OP_NOT\n",
+            "5. 0006    | OP_NOT\n",
+            "6. 0007    | OP_NIL\n",
+            "7. 0008    | OP_EQUAL\n",
+            "8. 0009    | OP_NOT\n",
+            "9. 0010    | OP_POP\n",
+            "10. 0011    | OP_NIL\n",
+            "11. 0012    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_string() {
+        let (chunk, heap) = compile_source("\"con\" + \"cat\";").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => con\n",
+            "2. 0002    | OP_CONSTANT     \t1 => cat\n",
+            "3. 0004    | OP_ADD\n",
+            "4. 0005    | OP_POP\n",
+            "5. 0006    | OP_NIL\n",
+            "6. 0007    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+        // two strings plus the script function itself
+        assert_eq!(heap.len(), 3);
+    }
+
+    #[test]
+    fn test_compile_interpolation() {
+        let (chunk, _heap) = compile_source("\"a ${1} b ${2}\";").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => a \n",
+            "2. 0002    | OP_CONSTANT     \t1 => 1\n",
+            "3. 0004    | OP_TO_STRING\n",
+            "4. 0005    | OP_ADD\n",
+            "5. 0006    | OP_CONSTANT     \t2 =>  b \n",
+            "6. 0008    | OP_ADD\n",
+            "7. 0009    | OP_CONSTANT     \t3 => 2\n",
+            "8. 0011    | OP_TO_STRING\n",
+            "9. 0012    | OP_ADD\n",
+            "10. 0013    | OP_POP\n",
+            "11. 0014    | OP_NIL\n",
+            "12. 0015    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+
+        let diagnostic = compile_error("print \"${1 2}\";");
+        assert_eq!(
+            diagnostic.message,
+            "Expect '}' after interpolated expression."
+        );
+        assert_eq!(diagnostic.label, "at '2'");
+        let diagnostic = compile_error("print \"${}\";");
+        assert_eq!(diagnostic.message, "Expect expression.");
+    }
+
+    #[test]
+    fn test_compile_deduplicates_constants() {
+        let (chunk, _heap) =
+            compile_source("var x = 1; print \"hi\"; x = x + 1.0; print \"hi\" + \"!\";").unwrap();
+        // x, 1 and the two strings: repeats reuse their first slot
+        assert_eq!(chunk.constants().len(), 4);
+
+        // Far more uses than one-byte operands could index, were each given its own slot.
+        let source = "var x = 0;".to_string() + &"x = x + 1; print \"x\";".repeat(1000);
+        let (chunk, _heap) = compile_source(&source).unwrap();
+        assert_eq!(chunk.constants().len(), 3);
+        assert!(chunk
+            .into_iter()
+            .all(|(_, [op, ..])| op != u8::from(OpCode::ConstantLong)));
+    }
+
+    #[test]
+    fn test_compile_long_operands() {
+        let terms: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        let source = format!("{};\nvar y;\nfun f() {{}}", terms.join(" + "));
+        let (chunk, _heap) = compile_source(&source).unwrap();
+        let disassembly = chunk.disassemble("test").unwrap();
+        let expected = concat!(
+            "601. 0988    2 OP_NIL\n",
+            "602. 0989    | OP_DEFINE_GLOBAL_LONG\t300 => y\n",
+            "603. 0993    3 OP_CLOSURE_LONG \t302 => <fn f>\n",
+            "604. 0997    | OP_DEFINE_GLOBAL_LONG\t301 => f\n",
+            "605. 1001    | OP_NIL\n",
+            "606. 1002    | OP_RETURN\n",
+        );
+        assert!(disassembly.ends_with(expected), "{}", disassembly);
+    }
+
+    #[test]
+    fn test_compile_globals() {
+        let (chunk, _heap) = compile_source("var a = 1; var b; b = a; print b;").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t1 => 1\n",
+            "2. 0002    | OP_DEFINE_GLOBAL\t0 => a\n",
+            "3. 0004    | OP_NIL\n",
+            "4. 0005    | OP_DEFINE_GLOBAL\t2 => b\n",
+            "5. 0007    | OP_GET_GLOBAL   \t0 => a\n",
+            "6. 0009    | OP_SET_GLOBAL   \t2 => b\n",
+            "7. 0011    | OP_POP\n",
+            "8. 0012    | OP_GET_GLOBAL   \t2 => b\n",
+            "9. 0014    | OP_PRINT\n",
+            "10. 0015    | OP_NIL\n",
+            "11. 0016    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_locals() {
+        let source = "{ var a = 1; { var b = a; a = b; } print a; }";
+        let (chunk, _heap) = compile_source(source).unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => 1\n",
+            "2. 0002    | OP_GET_LOCAL    \t1\n",
+            "3. 0004    | OP_GET_LOCAL    \t2\n",
+            "4. 0006    | OP_SET_LOCAL    \t1\n",
+            "5. 0008    | OP_POP\n",
+            "6. 0009    | OP_POP\n",
+            "7. 0010    | OP_GET_LOCAL    \t1\n",
+            "8. 0012    | OP_PRINT\n",
+            "9. 0013    | OP_POP\n",
+            "10. 0014    | OP_NIL\n",
+            "11. 0015    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_shadowing() {
+        // An inner local may shadow a global or an outer local...
+        let source = "var a = 1; { var b = a; { var a = b; print a; } }";
+        let (chunk, _heap) = compile_source(source).unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t1 => 1\n",
+            "2. 0002    | OP_DEFINE_GLOBAL\t0 => a\n",
+            "3. 0004    | OP_GET_GLOBAL   \t0 => a\n",
+            "4. 0006    | OP_GET_LOCAL    \t1\n",
+            "5. 0008    | OP_GET_LOCAL    \t2\n",
+            "6. 0010    | OP_PRINT\n",
+            "7. 0011    | OP_POP\n",
+            "8. 0012    | OP_POP\n",
+            "9. 0013    | OP_NIL\n",
+            "10. 0014    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+        // ...but not one declared in the same scope,
+        assert!(compile_source("{ var a = 1; var a = 2; }").is_err());
+        // and the shadowing local is in scope (but unusable) within its own initializer.
+        assert!(compile_source("var a = 1; { var a = a; }").is_err());
+    }
+
+    #[test]
+    fn test_compile_if_else() {
+        let (chunk, _heap) = compile_source("if (true) print 1; else print 2;").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_TRUE\n",
+            "2. 0001    | OP_JUMP_IF_FALSE\t1 -> 11\n",
+            "3. 0004    | OP_POP\n",
+            "4. 0005    | OP_CONSTANT     \t0 => 1\n",
+            "5. 0007    | OP_PRINT\n",
+            "6. 0008    | OP_JUMP         \t8 -> 15\n",
+            "7. 0011    | OP_POP\n",
+            "8. 0012    | OP_CONSTANT     \t1 => 2\n",
+            "9. 0014    | OP_PRINT\n",
+            "10. 0015    | OP_NIL\n",
+            "11. 0016    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_while_and_or() {
+        let (chunk, _heap) = compile_source("while (nil or false) nil and 1;").unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_NIL\n",
+            "2. 0001    | OP_JUMP_IF_FALSE\t1 -> 7\n",
+            "3. 0004    | OP_JUMP         \t4 -> 9\n",
+            "4. 0007    | OP_POP\n",
+            "5. 0008    | OP_FALSE\n",
+            "6. 0009    | OP_JUMP_IF_FALSE\t9 -> 24\n",
+            "7. 0012    | OP_POP\n",
+            "8. 0013    | OP_NIL\n",
+            "9. 0014    | OP_JUMP_IF_FALSE\t14 -> 20\n",
+            "10. 0017    | OP_POP\n",
+            "11. 0018    | OP_CONSTANT     \t0 => 1\n",
+            "12. 0020    | OP_POP\n",
+            "13. 0021    | OP_LOOP         \t21 -> 0\n",
+            "14. 0024    | OP_POP\n",
+            "15. 0025    | OP_NIL\n",
+            "16. 0026    | OP_RETURN\n",
+        );
+        assert_eq!(expected, chunk.disassemble("test").unwrap());
+    }
+
+    #[test]
+    fn test_compile_function() {
+        let source = "fun add(a, b) { return a + b; } print add(1, 2);";
+        let mut heap = Heap::new();
+        let script = compile(Scanner::new(source), &mut heap, &()).unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CLOSURE      \t1 => <fn add>\n",
+            "2. 0002    | OP_DEFINE_GLOBAL\t0 => add\n",
+            "3. 0004    | OP_GET_GLOBAL   \t0 => add\n",
+            "4. 0006    | OP_CONSTANT     \t2 => 1\n",
+            "5. 0008    | OP_CONSTANT     \t3 => 2\n",
+            "6. 0010    | OP_CALL         \t2\n",
+            "7. 0012    | OP_PRINT\n",
+            "8. 0013    | OP_NIL\n",
+            "9. 0014    | OP_RETURN\n",
+        );
+        let script = script.as_function().unwrap();
+        assert_eq!(expected, script.chunk.disassemble("test").unwrap());
+
+        let Some(add) = script.chunk.read_constant(1).and_then(|c| c.as_obj()) else {
+            panic!("expected a function constant");
+        };
+        let add = add.as_function().unwrap();
+        assert_eq!(add.arity, 2);
+        let expected = concat!(
+            "== add ==\n",
+            "1. 0000    1 OP_GET_LOCAL    \t1\n",
+            "2. 0002    | OP_GET_LOCAL    \t2\n",
+            "3. 0004    | OP_ADD\n",
+            "4. 0005    | OP_RETURN\n",
+            "5. 0006    | OP_NIL\n",
+            "6. 0007    | OP_RETURN\n",
+        );
+        assert_eq!(expected, add.chunk.disassemble("add").unwrap());
+    }
+
+    #[test]
+    fn test_compile_closure() {
+        let source = "{ var x = 1; fun outer() { fun inner() { return x; } } }";
+        let mut heap = Heap::new();
+        let script = compile(Scanner::new(source), &mut heap, &()).unwrap();
+        let script = script.as_function().unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CONSTANT     \t0 => 1\n",
+            "2. 0002    | OP_CLOSURE      \t1 => <fn outer>\n",
+            "0004      |                     local 1\n",
+            "3. 0006    | OP_POP\n",
+            "4. 0007    | OP_CLOSE_UPVALUE\n",
+            "5. 0008    | OP_NIL\n",
+            "6. 0009    | OP_RETURN\n",
+        );
+        assert_eq!(expected, script.chunk.disassemble("test").unwrap());
+
+        let Some(outer) = script.chunk.read_constant(1).and_then(|c| c.as_obj()) else {
+            panic!("expected a function constant");
+        };
+        let outer = outer.as_function().unwrap();
+        assert_eq!(outer.upvalue_count, 1);
+        // `inner` reaches `x` through the upvalue `outer` captured.
+        let expected = concat!(
+            "== outer ==\n",
+            "1. 0000    1 OP_CLOSURE      \t0 => <fn inner>\n",
+            "0002      |                     upvalue 0\n",
+            "2. 0004    | OP_NIL\n",
+            "3. 0005    | OP_RETURN\n",
+        );
+        assert_eq!(expected, outer.chunk.disassemble("outer").unwrap());
+
+        let Some(inner) = outer.chunk.read_constant(0).and_then(|c| c.as_obj()) else {
+            panic!("expected a function constant");
+        };
+        let inner = inner.as_function().unwrap();
+        let expected = concat!(
+            "== inner ==\n",
+            "1. 0000    1 OP_GET_UPVALUE  \t0\n",
+            "2. 0002    | OP_RETURN\n",
+            "3. 0003    | OP_NIL\n",
+            "4. 0004    | OP_RETURN\n",
+        );
+        assert_eq!(expected, inner.chunk.disassemble("inner").unwrap());
+    }
+
+    #[test]
+    fn test_compile_class() {
+        let source = "class A { init(x) { this.x = x; } } A(1).get(2);";
+        let mut heap = Heap::new();
+        let script = compile(Scanner::new(source), &mut heap, &()).unwrap();
+        let script = script.as_function().unwrap();
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CLASS        \t0 => A\n",
+            "2. 0002    | OP_DEFINE_GLOBAL\t0 => A\n",
+            "3. 0004    | OP_GET_GLOBAL   \t0 => A\n",
+            "4. 0006    | OP_CLOSURE      \t2 => <fn init>\n",
+            "5. 0008    | OP_METHOD       \t1 => init\n",
+            "6. 0010    | OP_POP\n",
+            "7. 0011    | OP_GET_GLOBAL   \t0 => A\n",
+            "8. 0013    | OP_CONSTANT     \t3 => 1\n",
+            "9. 0015    | OP_CALL         \t1\n",
+            "10. 0017    | OP_CONSTANT     \t5 => 2\n",
+            "11. 0019    | OP_INVOKE       \t(1 args) 4 => get\n",
+            "12. 0022    | OP_POP\n",
+            "13. 0023    | OP_NIL\n",
+            "14. 0024    | OP_RETURN\n",
+        );
+        assert_eq!(expected, script.chunk.disassemble("test").unwrap());
+
+        let Some(init) = script.chunk.read_constant(2).and_then(|c| c.as_obj()) else {
+            panic!("expected a function constant");
+        };
+        // Initializers implicitly return `this`, which lives in slot zero.
+        let expected = concat!(
+            "== init ==\n",
+            "1. 0000    1 OP_GET_LOCAL    \t0\n",
+            "2. 0002    | OP_GET_LOCAL    \t1\n",
+            "3. 0004    | OP_SET_PROPERTY \t0 => x\n",
+            "4. 0006    | OP_POP\n",
+            "5. 0007    | OP_GET_LOCAL    \t0\n",
+            "6. 0009    | OP_RETURN\n",
+        );
+        let init = init.as_function().unwrap();
+        assert_eq!(expected, init.chunk.disassemble("init").unwrap());
+    }
+
+    #[test]
+    fn test_compile_super() {
+        let source = "class A {} class B < A { m() { super.m(1); } }";
+        let mut heap = Heap::new();
+        let script = compile(Scanner::new(source), &mut heap, &()).unwrap();
+        let script = script.as_function().unwrap();
+        // The superclass stays on the stack as the local `super` until the class body ends,
+        // where it is closed over since the method captured it.
+        let expected = concat!(
+            "== test ==\n",
+            "1. 0000    1 OP_CLASS        \t0 => A\n",
+            "2. 0002    | OP_DEFINE_GLOBAL\t0 => A\n",
+            "3. 0004    | OP_GET_GLOBAL   \t0 => A\n",
+            "4. 0006    | OP_POP\n",
+            "5. 0007    | OP_CLASS        \t1 => B\n",
+            "6. 0009    | OP_DEFINE_GLOBAL\t1 => B\n",
+            "7. 0011    | OP_GET_GLOBAL   \t0 => A\n",
+            "8. 0013    | OP_GET_GLOBAL   \t1 => B\n",
+            "9. 0015    | OP_INHERIT\n",
+            "10. 0016    | OP_GET_GLOBAL   \t1 => B\n",
+            "11. 0018    | OP_CLOSURE      \t3 => <fn m>\n",
+            "0020      |                     local 1\n",
+            "12. 0022    | OP_METHOD       \t2 => m\n",
+            "13. 0024    | OP_POP\n",
+            "14. 0025    | OP_CLOSE_UPVALUE\n",
+            "15. 0026    | OP_NIL\n",
+            "16. 0027    | OP_RETURN\n",
+        );
+        assert_eq!(expected, script.chunk.disassemble("test").unwrap());
+
+        let Some(method) = script.chunk.read_constant(3).and_then(|c| c.as_obj()) else {
+            panic!("expected a function constant");
+        };
+        let expected = concat!(
+            "== m ==\n",
+            "1. 0000    1 OP_GET_LOCAL    \t0\n",
+            "2. 0002    | OP_CONSTANT     \t1 => 1\n",
+            "3. 0004    | OP_GET_UPVALUE  \t0\n",
+            "4. 0006    | OP_SUPER_INVOKE \t(1 args) 0 => m\n",
+            "5. 0009    | OP_POP\n",
+            "6. 0010    | OP_NIL\n",
+            "7. 0011    | OP_RETURN\n",
+        );
+        let method = method.as_function().unwrap();
+        assert_eq!(expected, method.chunk.disassemble("m").unwrap());
+    }
+
+    #[test]
+    fn test_compile_too_many_parameters() {
+        // The scanner only accepts letters in identifiers, so spell the index out.
+        let params: Vec<String> = (0..=MAX_ARITY)
+            .map(|i| {
+                format!(
+                    "p{}{}",
+                    (b'a' + (i / 26) as u8) as char,
+                    (b'a' + (i % 26) as u8) as char
+                )
+            })
+            .collect();
+        let source = format!("fun f({}) {{}}", params.join(", "));
+        assert!(compile_source(&source).is_err());
+        let source = format!("fun f({}) {{}}", params[..MAX_ARITY].join(", "));
+        assert!(compile_source(&source).is_ok());
+    }
+
+    #[test]
+    fn test_compile_too_many_locals() {
+        let mut source = String::from("{");
+        for i in 0..=MAX_LOCALS {
+            source.push_str(&format!("var v{} = nil;", i));
+        }
+        source.push('}');
+        assert!(compile_source(&source).is_err());
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        for source in [
+            "1 +;",
+            "(1;",
+            "* 2;",
+            "1 2;",
+            "1 + 2",
+            "print 1",
+            "var 1 = 2;",
+            "var a = 1",
+            "a + b = c;",
+            "-a = 1;",
+            "{ var a = 1;",
+            "{ var a = a; }",
+            "if true print 1;",
+            "while (true print 1;",
+            "for (var i = 0; i < 1) print i;",
+            "return 1;",
+            "fun f(a, a) {}",
+            "fun f(a b) {}",
+            "fun (a) {}",
+            "f(1, 2;",
+        ] {
+            let result = compile_source(source);
+            assert!(
+                matches!(result, Err(InterpreterError::CompileError(_))),
+                "expected compile error for {:?}",
+                source
+            );
+        }
+    }
+
+    /// The single error reported for `source`.
+    fn compile_error(source: &str) -> SourceDiagnostic {
+        let Err(InterpreterError::CompileError(diagnostics)) = compile_source(source) else {
+            panic!("expected a compile error for {:?}", source);
+        };
+        let [diagnostic] = &diagnostics.errors[..] else {
+            panic!("expected one error for {:?}", source);
+        };
+        diagnostic.clone()
+    }
+
+    #[test]
+    fn test_compile_error_diagnostic() {
+        let diagnostic = compile_error("print \"é\" x;");
+        assert_eq!(diagnostic.message, "Expect ';' after value.");
+        assert_eq!(diagnostic.label, "at 'x'");
+        assert_eq!(diagnostic.span, (11, 1).into());
+
+        let diagnostic = compile_error("print 1");
+        assert_eq!(diagnostic.label, "at end");
+        assert_eq!(diagnostic.span, (7, 0).into());
+    }
+
+    #[test]
+    fn test_compile_reports_every_error() {
+        let source = "var a = ;\nprint a +;\nfun f(a, 1) {\n  print a;\n}\nprint 1 2;\nprint a;";
+        let Err(InterpreterError::CompileError(diagnostics)) = compile_source(source) else {
+            panic!("expected a compile error");
+        };
+        let errors: Vec<_> = diagnostics
+            .errors
+            .iter()
+            .map(|error| (error.message.as_str(), error.label.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("Expect expression.", "at ';'"),
+                ("Expect expression.", "at ';'"),
+                ("Expect parameter name.", "at '1'"),
+                ("Expect ';' after value.", "at '2'"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_synchronizes_within_function() {
+        // the body is still compiled as the function's, so returning from it is fine
+        let diagnostic = compile_error("fun f(a, 1) {\n  return a;\n}");
+        assert_eq!(diagnostic.message, "Expect parameter name.");
+
+        // the block is still compiled as the body of the `if`, so its brace is matched
+        let diagnostic = compile_error("if (x  {\n  print 1;\n}");
+        assert_eq!(diagnostic.message, "Expect ')' after condition.");
+
+        // errors in the condition don't leave the loop's scope open
+        let diagnostic = compile_error("for (var i = 0; i < 1) print i;\nvar i = 1; var i = 2;");
+        assert_eq!(diagnostic.message, "Expect ';' after loop condition.");
+
+        // and later statements of the same body are still checked
+        let source = "fun f() {\n  print 1 2;\n  var a = 1;\n  var a = 2;\n}";
+        let Err(InterpreterError::CompileError(diagnostics)) = compile_source(source) else {
+            panic!("expected a compile error");
+        };
+        let messages: Vec<_> = diagnostics
+            .errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Expect ';' after value.",
+                "Already a variable with this name in this scope."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_reports_errors_from_tokens() {
+        // Tokens need not come from a scanner: errors from elsewhere fail the compilation.
+        let mut heap = Heap::new();
+        let tokens = Scanner::new("print 1;\nprint 2;")
+            .enumerate()
+            .map(|(i, token)| match i {
+                3 => Err(InterpreterError::RuntimeError),
+                _ => token,
+            });
+        let Err(InterpreterError::CompileError(diagnostics)) = compile(tokens, &mut heap, &())
+        else {
+            panic!("expected a compile error");
+        };
+        let [diagnostic] = &diagnostics.errors[..] else {
+            panic!("expected one error");
+        };
+        assert_eq!(diagnostic.message, "Runtime error");
+        assert_eq!(diagnostic.label, "at ';'");
+    }
+
+    #[test]
+    fn test_compile_records_spans() {
+        let (chunk, _heap) = compile_source("print 1 +\n 2;").unwrap();
+        // constants can't fail, so only the addition has a span, pointing at its operator
+        assert_eq!(chunk.span_at(0), None);
+        assert_eq!(chunk.span_at(2), None);
+        assert_eq!(chunk.span_at(4), Some((8, 1).into()));
+        assert_eq!(chunk.line_at(4), Some(1));
+        assert_eq!(chunk.line_at(2), Some(2));
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt;
 use std::option::Option;
 
-use miette::Diagnostic;
+use miette::{Diagnostic, SourceSpan};
 use thiserror::Error;
 
 #[derive(Error, Diagnostic, Debug)]
@@ -23,23 +23,91 @@ pub enum CloxersError {
 
     #[error("TypeError: {0}")]
     TypeError(String),
+
+    #[error("Undefined variable '{0}'")]
+    UndefinedVariable(String),
+
+    #[error("Undefined property '{0}'")]
+    UndefinedProperty(String),
+
+    #[error("Can only call functions and classes")]
+    NotCallable,
+
+    #[error("Expected {expected} arguments but got {got}")]
+    ArityMismatch { expected: usize, got: usize },
+
+    #[error("Stack overflow")]
+    StackOverflow,
+}
+
+/// A problem with the program, pointing at the part of the source code it concerns.
+///
+/// The source itself is attached when the diagnostic is reported, so that miette can show
+/// the offending snippet with `label` underneath the span.
+#[derive(Error, Diagnostic, Debug, Clone, PartialEq)]
+#[error("{message}")]
+pub struct SourceDiagnostic {
+    pub message: String,
+    pub label: String,
+    #[label("{label}")]
+    pub span: SourceSpan,
+}
+
+impl SourceDiagnostic {
+    pub fn new(message: impl Into<String>, label: impl Into<String>, span: SourceSpan) -> Self {
+        Self {
+            message: message.into(),
+            label: label.into(),
+            span,
+        }
+    }
 }
 
+/// Several diagnostics found in one pass over the source, reported together.
+#[derive(Error, Diagnostic, Debug, Clone, PartialEq)]
+#[error("{message}")]
+pub struct Diagnostics {
+    pub message: String,
+    #[related]
+    pub errors: Vec<SourceDiagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new(message: impl Into<String>, errors: Vec<SourceDiagnostic>) -> Self {
+        Self {
+            message: message.into(),
+            errors,
+        }
+    }
+}
 
 #[derive(Error, Diagnostic, Debug)]
 pub enum InterpreterError {
-    CompileError,
+    CompileError(Diagnostics),
     RuntimeError,
-    ScannerError(Option<String>),
+    ScannerError(Diagnostics),
 }
 
 impl fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            InterpreterError::CompileError => write!(f, "Compile error"),
+            InterpreterError::CompileError(_) => write!(f, "Compile error"),
             InterpreterError::RuntimeError => write!(f, "Runtime error"),
-            InterpreterError::ScannerError(Some(s)) => write!(f, "Scanner error: {}", s),
-            InterpreterError::ScannerError(None) => write!(f, "Scanner error"),
+            InterpreterError::ScannerError(_) => write!(f, "Scanner error"),
         }
     }
 }
+
+impl InterpreterError {
+    /// Exit code following the conventions used by clox (see `sysexits.h`).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InterpreterError::CompileError(_) | InterpreterError::ScannerError(_) => 65,
+            InterpreterError::RuntimeError => 70,
+        }
+    }
+
+    pub fn exit(&self) -> ! {
+        std::process::exit(self.exit_code())
+    }
+}
@@ -0,0 +1,760 @@
+use std::io::Write;
+
+use miette::{Diagnostic, Report};
+
+use crate::compiler;
+use crate::error::{InterpreterError, SourceDiagnostic};
+use crate::memory::GcMode;
+use crate::scanner::Scanner;
+use crate::vm::VM;
+
+/// Ties together the scanner, compiler and virtual machine:
+/// source text goes in one end and a program runs out the other.
+pub struct Interpreter {
+    vm: VM,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self { vm: VM::new() }
+    }
+
+    /// Creates an interpreter whose `print` statements write to `out` instead of stdout.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        Self {
+            vm: VM::with_output(out),
+        }
+    }
+
+    /// Creates an interpreter writing to `out` whose heap is collected in the given mode.
+    pub fn with_gc_mode(out: Box<dyn Write>, mode: GcMode) -> Self {
+        Self {
+            vm: VM::with_gc_mode(out, mode),
+        }
+    }
+
+    /// Collects garbage after every allocation: slow, but quick to expose objects freed too early.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.vm.heap_mut().set_stress(stress);
+    }
+
+    /// Throws away any stack values and call frames left over from a previous run.
+    pub fn reset(&mut self) {
+        self.vm.reset_stack();
+    }
+
+    /// Scans, compiles and executes a Lox program.
+    pub fn run(&mut self, source: &str) -> Result<(), InterpreterError> {
+        self.reset();
+        let (heap, roots) = self.vm.heap_and_roots();
+        let function = compiler::compile(Scanner::new(source), heap, &roots)
+            .inspect_err(|error| Self::report(error, source))?;
+        self.vm.interpret(function).map_err(|report| {
+            match self.vm.error_span() {
+                Some(span) => Self::print_diagnostic(
+                    SourceDiagnostic::new(report.to_string(), "raised here", span),
+                    source,
+                ),
+                None => eprintln!("{:?}", report),
+            }
+            for line in self.vm.stack_trace() {
+                eprintln!("{}", line);
+            }
+            self.vm.reset_stack();
+            InterpreterError::RuntimeError
+        })
+    }
+
+    /// Prints the diagnostics carried by a scanner or compiler error.
+    fn report(error: &InterpreterError, source: &str) {
+        if let InterpreterError::ScannerError(diagnostics)
+        | InterpreterError::CompileError(diagnostics) = error
+        {
+            Self::print_diagnostic(diagnostics.clone(), source);
+        }
+    }
+
+    /// Prints `diagnostic` along with the snippets of `source` it points at.
+    fn print_diagnostic(diagnostic: impl Diagnostic + Send + Sync + 'static, source: &str) {
+        eprintln!(
+            "{:?}",
+            Report::new(diagnostic).with_source_code(source.to_string())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Collects everything the program prints so tests can inspect it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(source: &str) -> (Result<(), InterpreterError>, String) {
+        let buffer = SharedBuffer::default();
+        let mut interpreter = Interpreter::with_output(Box::new(buffer.clone()));
+        let result = interpreter.run(source);
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn test_run_expression() {
+        let (result, output) = run("print (1 + 2) * -3;");
+        assert!(result.is_ok());
+        assert_eq!(output, "-9\n");
+    }
+
+    #[test]
+    fn test_run_many_constants() {
+        // Past 256 constants, literals are loaded with a 24-bit index.
+        let terms: Vec<String> = (0..300).map(|i| format!("{}.5", i)).collect();
+        let (result, output) = run(&format!("print {};", terms.join(" + ")));
+        assert!(result.is_ok());
+        assert_eq!(output, "45000\n");
+    }
+
+    #[test]
+    fn test_run_names_after_many_constants() {
+        // Names are constants too: past 256, instructions referring to them take a 24-bit index.
+        let terms: Vec<String> = (0..300).map(|i| format!("{}.5", i)).collect();
+        let terms = terms.join(" + ");
+        let source = format!(
+            r#"
+            print {terms};
+            var y = 1;
+            print y;
+            y = 2;
+            fun f(a) {{
+                print {terms};
+                fun g() {{ return a + y; }}
+                return g;
+            }}
+            print f(1)();
+            class A {{
+                m() {{ return "A.m"; }}
+            }}
+            class B < A {{
+                init() {{ this.x = 3; }}
+                m() {{
+                    print {terms};
+                    return super.m() + " via B";
+                }}
+            }}
+            var b = B();
+            print b.x;
+            b.x = 4;
+            print b.x;
+            print b.m();
+            "#
+        );
+        let (result, output) = run(&source);
+        assert!(result.is_ok());
+        assert_eq!(output, "45000\n1\n45000\n3\n3\n4\n45000\nA.m via B\n");
+    }
+
+    #[test]
+    fn test_run_string_concatenation() {
+        let (result, output) = run("print \"a\" + \"b\" == \"ab\"; print \"a\" + \"b\";");
+        assert!(result.is_ok());
+        assert_eq!(output, "true\nab\n");
+    }
+
+    #[test]
+    fn test_run_string_escapes() {
+        let (result, output) =
+            run(r#"print "a\tb\n\"c\" \\ \u{e9}\u{1F600}"; print "é" == "\u{E9}";"#);
+        assert!(result.is_ok());
+        assert_eq!(output, "a\tb\n\"c\" \\ é😀\ntrue\n");
+    }
+
+    #[test]
+    fn test_run_string_interpolation() {
+        let source = r#"
+            var name = "Ada";
+            var age = 36;
+            print "Hello ${name}, you are ${age + 1}";
+            print "${nil}${true}${1.5}";
+            print "nested ${"inner ${name + "!"}"}, braces ${ "{" + "}" }, \${escaped}";
+            class Point {}
+            fun f() {}
+            print "${Point} ${Point()} ${f}";
+        "#;
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            concat!(
+                "Hello Ada, you are 37\n",
+                "niltrue1.5\n",
+                "nested inner Ada!, braces {}, ${escaped}\n",
+                "Point Point instance <fn f>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_run_globals() {
+        let source = "
+            var breakfast = \"beignets\";
+            var beverage = \"cafe au lait\";
+            breakfast = \"beignets with \" + beverage;
+            print breakfast;
+            var unset;
+            print unset;
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "beignets with cafe au lait\nnil\n");
+    }
+
+    #[test]
+    fn test_run_block_scopes() {
+        let source = "
+            var a = \"global a\";
+            var b = \"global b\";
+            {
+                var a = \"outer a\";
+                {
+                    var a = \"inner a\";
+                    b = a;
+                    print a;
+                }
+                print a;
+            }
+            print a;
+            print b;
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "inner a\nouter a\nglobal a\ninner a\n");
+    }
+
+    #[test]
+    fn test_run_control_flow() {
+        let source = "
+            if (1 > 2) print \"no\"; else print \"yes\";
+            if (nil) print \"no\";
+            var i = 0;
+            while (i < 3) { print i; i = i + 1; }
+            for (var j = 0; j < 3; j = j + 1) print j * 10;
+            var k = 1;
+            for (; k < 100;) k = k * 2;
+            print k;
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "yes\n0\n1\n2\n0\n10\n20\n128\n");
+    }
+
+    #[test]
+    fn test_run_logical_operators() {
+        let source = "
+            print nil or \"default\";
+            print 1 and 2;
+            print false and undefined;
+            print true or undefined;
+            print nil and 1 or 3;
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "default\n2\nfalse\ntrue\n3\n");
+    }
+
+    #[test]
+    fn test_run_functions() {
+        let source = "
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            print fib(10);
+            fun greet(name) { print \"hi \" + name; }
+            print greet(\"bob\");
+            print fib;
+            fun noop() {}
+            print noop();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "55\nhi bob\nnil\n<fn fib>\nnil\n");
+    }
+
+    #[test]
+    fn test_run_function_errors() {
+        let (result, _) = run("fun f(a, b) {} f(1);");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        let (result, _) = run("var x = 1; x();");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        let (result, _) = run("fun recurse() { recurse(); } recurse();");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        let (result, _) = run("return 1;");
+        assert!(matches!(result, Err(InterpreterError::CompileError(_))));
+    }
+
+    #[test]
+    fn test_run_closure_counter() {
+        let source = "
+            fun makeCounter() {
+                var count = 0;
+                fun counter() {
+                    count = count + 1;
+                    return count;
+                }
+                return counter;
+            }
+            var a = makeCounter();
+            var b = makeCounter();
+            print a();
+            print a();
+            print b();
+            print a();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n2\n1\n3\n");
+    }
+
+    #[test]
+    fn test_run_closures_share_variables() {
+        let source = "
+            var get;
+            var set;
+            fun main() {
+                var shared = \"initial\";
+                fun getter() { print shared; }
+                fun setter() { shared = \"updated\"; }
+                get = getter;
+                set = setter;
+                getter();
+                setter();
+                print shared;
+            }
+            main();
+            get();
+            set();
+            get();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "initial\nupdated\nupdated\nupdated\n");
+    }
+
+    #[test]
+    fn test_run_closures_capture_per_scope() {
+        // Each iteration's block-local variable is a distinct variable,
+        // closed over when its scope ends.
+        let source = "
+            var first;
+            var second;
+            for (var i = 1; i < 3; i = i + 1) {
+                var j = i;
+                fun capture() { return j; }
+                if (first == nil) first = capture; else second = capture;
+            }
+            print first();
+            print second();
+            fun outer() {
+                var x = \"outer\";
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                return middle;
+            }
+            print outer()()();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n2\nouter\n");
+    }
+
+    #[test]
+    fn test_run_classes() {
+        let source = "
+            class Pair {}
+            print Pair;
+            var pair = Pair();
+            print pair;
+            pair.first = 1;
+            pair.second = 2;
+            print pair.first + pair.second;
+            print pair.first = 3;
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "Pair\nPair instance\n3\n3\n");
+    }
+
+    #[test]
+    fn test_run_methods() {
+        let source = "
+            class Scone {
+                topping(first, second) {
+                    print \"scone with \" + first + \" and \" + second;
+                }
+            }
+            var scone = Scone();
+            scone.topping(\"berries\", \"cream\");
+            class Nested {
+                method() {
+                    fun function() { print this; }
+                    function();
+                }
+            }
+            Nested().method();
+            var bound = scone.topping;
+            print bound;
+            bound(\"jam\", \"butter\");
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "scone with berries and cream\nNested instance\n<fn topping>\nscone with jam and butter\n"
+        );
+    }
+
+    #[test]
+    fn test_run_initializers() {
+        let source = "
+            class CoffeeMaker {
+                init(coffee) {
+                    this.coffee = coffee;
+                    if (coffee == nil) return;
+                    this.ready = true;
+                }
+                brew() {
+                    print \"Enjoy your cup of \" + this.coffee;
+                    this.coffee = nil;
+                }
+            }
+            var maker = CoffeeMaker(\"coffee and chicory\");
+            maker.brew();
+            print maker.coffee;
+            print maker.init(\"tea\") == maker;
+            print CoffeeMaker(nil).coffee;
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "Enjoy your cup of coffee and chicory\nnil\ntrue\nnil\n"
+        );
+    }
+
+    #[test]
+    fn test_run_invoke_field() {
+        // A field holding a function is called in preference to a method of the same name.
+        let source = "
+            class Box { method() { print \"method\"; } }
+            fun field() { print \"field\"; }
+            var box = Box();
+            box.method();
+            box.method = field;
+            box.method();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "method\nfield\n");
+    }
+
+    #[test]
+    fn test_run_inheritance() {
+        let source = "
+            class Doughnut {
+                init(filling) { this.filling = filling; }
+                cook() { print \"Fry until golden brown.\"; }
+                describe() { return \"doughnut with \" + this.filling; }
+            }
+            class BostonCream < Doughnut {
+                init() { super.init(\"custard\"); }
+                cook() {
+                    super.cook();
+                    print \"Pipe full of \" + this.filling + \".\";
+                }
+            }
+            var cream = BostonCream();
+            cream.cook();
+            print cream.describe();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "Fry until golden brown.\nPipe full of custard.\ndoughnut with custard\n"
+        );
+    }
+
+    #[test]
+    fn test_run_super_bound_method() {
+        let source = "
+            class A { method() { return \"A method on \" + this.name; } }
+            class B < A {
+                method() { return \"B method\"; }
+                test() {
+                    var parent = super.method;
+                    return parent();
+                }
+            }
+            var b = B();
+            b.name = \"b\";
+            print b.test();
+            print b.method();
+        ";
+        let (result, output) = run(source);
+        assert!(result.is_ok());
+        assert_eq!(output, "A method on b\nB method\n");
+    }
+
+    #[test]
+    fn test_run_inheritance_errors() {
+        let (result, _) = run("var NotClass = 1; class A < NotClass {}");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        let (result, _) = run("class A {} class B < A { m() { super.missing(); } } B().m();");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        for source in [
+            "class A < A {}",
+            "super.method();",
+            "class A { m() { super.m(); } }",
+            "class A {} class B < A { m() { super; } }",
+            "class A {} class B < A { m() { super.; } }",
+        ] {
+            let (result, _) = run(source);
+            assert!(
+                matches!(result, Err(InterpreterError::CompileError(_))),
+                "expected compile error for {:?}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_class_errors() {
+        for source in [
+            "class A {} A().missing;",
+            "class A {} A().missing();",
+            "var x = 1; x.field;",
+            "var x = 1; x.field = 2;",
+            "var x = 1; x.method();",
+            "class A {} A(1);",
+            "class A { init(a) {} } A();",
+        ] {
+            let (result, _) = run(source);
+            assert!(
+                matches!(result, Err(InterpreterError::RuntimeError)),
+                "expected runtime error for {:?}",
+                source
+            );
+        }
+        for source in [
+            "print this;",
+            "fun f() { this; }",
+            "class A { init() { return 1; } }",
+        ] {
+            let (result, _) = run(source);
+            assert!(
+                matches!(result, Err(InterpreterError::CompileError(_))),
+                "expected compile error for {:?}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_runtime_error_resets_frames() {
+        let buffer = SharedBuffer::default();
+        let mut interpreter = Interpreter::with_output(Box::new(buffer.clone()));
+        let result = interpreter.run("fun f() { return 1 + nil; } f();");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        interpreter.run("print f;").unwrap();
+        assert_eq!(buffer.0.borrow().as_slice(), b"<fn f>\n");
+    }
+
+    /// Programs exercising every kind of object, with the output each should print.
+    const GC_PROGRAMS: [(&str, &str); 5] = [
+        (
+            // Capturing each variable allocates an upvalue, before the closure itself.
+            "
+            fun make(a, b) {
+                var c = a + b;
+                fun f() { return a + b + c; }
+                return f;
+            }
+            print make(\"x\", \"y\")();
+            ",
+            "xyxy\n",
+        ),
+        (
+            "var s = \"\"; for (var i = 0; i < 10; i = i + 1) s = s + \"x\"; print s;",
+            "xxxxxxxxxx\n",
+        ),
+        (
+            "
+            fun makeCounter() {
+                var count = 0;
+                fun counter() { count = count + 1; return \"count \" + \"is\"; }
+                return counter;
+            }
+            var counter = makeCounter();
+            counter();
+            print counter();
+            ",
+            "count is\n",
+        ),
+        (
+            "
+            class Node {
+                init(value, next) { this.value = value; this.next = next; }
+                sum() {
+                    if (this.next == nil) return this.value;
+                    return this.value + this.next.sum();
+                }
+            }
+            var list = nil;
+            for (var i = 1; i <= 10; i = i + 1) list = Node(i, list);
+            print list.sum();
+            ",
+            "55\n",
+        ),
+        (
+            "
+            class A { greet() { return \"hello from \" + this.name; } }
+            class B < A {
+                init() { this.name = \"b\" + \"!\"; }
+                greet() { var parent = super.greet; return parent() + \"?\"; }
+            }
+            print B().greet();
+            ",
+            "hello from b!?\n",
+        ),
+    ];
+
+    const GC_MODES: [GcMode; 2] = [GcMode::StopTheWorld, GcMode::Incremental];
+
+    #[test]
+    fn test_gc_stress() {
+        for mode in GC_MODES {
+            for (source, expected) in GC_PROGRAMS {
+                let buffer = SharedBuffer::default();
+                let mut interpreter = Interpreter::with_gc_mode(Box::new(buffer.clone()), mode);
+                interpreter.set_gc_stress(true);
+                interpreter.run(source).unwrap();
+                let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+                assert_eq!(output, expected, "for {} in {:?} mode", source, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gc_frees_garbage_between_runs() {
+        for mode in GC_MODES {
+            let buffer = SharedBuffer::default();
+            let mut interpreter = Interpreter::with_gc_mode(Box::new(buffer.clone()), mode);
+            interpreter.set_gc_stress(true);
+            interpreter.run("var kept = \"kept\" + \"!\";").unwrap();
+            let live = interpreter.vm.heap_mut().len();
+            // Only the garbage from this run is collected: the global from the last one survives.
+            interpreter
+                .run("for (var i = 0; i < 100; i = i + 1) { var s = \"a\" + \"b\"; }")
+                .unwrap();
+            interpreter.run("print kept;").unwrap();
+            assert!(
+                interpreter.vm.heap_mut().len() <= live + 10,
+                "{:?} mode",
+                mode
+            );
+            assert_eq!(buffer.0.borrow().as_slice(), b"kept!\n");
+        }
+    }
+
+    #[test]
+    fn test_globals_persist_between_runs() {
+        let buffer = SharedBuffer::default();
+        let mut interpreter = Interpreter::with_output(Box::new(buffer.clone()));
+        interpreter.run("var a = 1;").unwrap();
+        interpreter.reset();
+        interpreter.run("print a + 1;").unwrap();
+        assert_eq!(buffer.0.borrow().as_slice(), b"2\n");
+    }
+
+    #[test]
+    fn test_escaped_closure_survives_runtime_error() {
+        let buffer = SharedBuffer::default();
+        let mut interpreter = Interpreter::with_output(Box::new(buffer.clone()));
+        let result = interpreter
+            .run("var f; fun g() { var x = 1; fun h() { return x; } f = h; nil(); } g();");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        interpreter.run("print f();").unwrap();
+        assert_eq!(buffer.0.borrow().as_slice(), b"1\n");
+    }
+
+    #[test]
+    fn test_undefined_global() {
+        let (result, output) = run("print 1; print missing;");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        assert_eq!(output, "1\n");
+        let (result, _) = run("missing = 1;");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+    }
+
+    #[test]
+    fn test_runtime_error_exit_code() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.run("\"a\" + 1;");
+        assert!(matches!(result, Err(InterpreterError::RuntimeError)));
+        assert_eq!(result.unwrap_err().exit_code(), 70);
+    }
+
+    #[test]
+    fn test_runtime_error_span() {
+        let mut interpreter = Interpreter::new();
+        let source = "fun f(a) {\n  return -a;\n}\nprint f(\"é\");";
+        // `run` resets the stack after reporting the error, so drive the VM directly.
+        let (heap, roots) = interpreter.vm.heap_and_roots();
+        let function = compiler::compile(Scanner::new(source), heap, &roots).unwrap();
+        assert!(interpreter.vm.interpret(function).is_err());
+        let span = interpreter.vm.error_span().unwrap();
+        assert_eq!(&source[span.offset()..span.offset() + span.len()], "-");
+        assert_eq!(
+            interpreter.vm.stack_trace(),
+            vec!["[line 2] in f()", "[line 4] in script"]
+        );
+    }
+
+    #[test]
+    fn test_compile_error_exit_code() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.run("1 +;");
+        assert!(matches!(result, Err(InterpreterError::CompileError(_))));
+        assert_eq!(result.unwrap_err().exit_code(), 65);
+    }
+
+    #[test]
+    fn test_scanner_error_exit_code() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.run("@");
+        assert!(matches!(result, Err(InterpreterError::ScannerError(_))));
+        assert_eq!(result.unwrap_err().exit_code(), 65);
+    }
+}
@@ -1,8 +1,14 @@
 pub mod chunk;
 pub mod compiler;
 pub mod error;
+pub mod interpreter;
+pub mod memory;
+pub mod object;
 pub mod opcodes;
 pub mod scanner;
+pub mod table;
 pub mod token;
 pub mod value;
 pub mod vm;
+
+pub use interpreter::Interpreter;
@@ -1,10 +1,8 @@
 use clap::Parser;
 use std::io::{self, Write};
 
-use cloxers::chunk::Chunk;
-use cloxers::opcodes::OpCode;
-use cloxers::vm::VM;
-use cloxers::value::Value;
+use cloxers::memory::GcMode;
+use cloxers::Interpreter;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -13,10 +11,17 @@ struct Args {
     /// Lox program to run (if not provided, runs in REPL mode)
     #[arg(short, long, default_missing_value = "")]
     filename: Option<String>,
+
+    /// Run the garbage collector after every allocation (slow: for testing the collector)
+    #[arg(long)]
+    gc_stress: bool,
+
+    /// Collect garbage incrementally, in small steps between instructions, for shorter pauses
+    #[arg(long)]
+    incremental_gc: bool,
 }
 
-fn run_prompt() {
-    let mut interpreter = Interpreter::new();
+fn run_prompt(mut interpreter: Interpreter) {
     loop {
         // If there's an error, we want to keep running the REPL
         interpreter.reset();
@@ -31,10 +36,8 @@ fn run_prompt() {
     }
 }
 
-fn run_file(filename: &str) {
+fn run_file(mut interpreter: Interpreter, filename: &str) {
     let source = std::fs::read_to_string(filename).unwrap();
-    todo!("Run file: {}", source);
-    let mut interpreter = Interpreter::new();
     match interpreter.run(&source) {
         Ok(_) => (),
         Err(e) => {
@@ -46,18 +49,17 @@ fn run_file(filename: &str) {
 
 fn main() {
     let args = Args::parse();
-    let mut chunk = Chunk::new();
-    let _ = chunk.write_constant(Value::Number(1.2), 1);
-    let _ = chunk.write_constant(Value::Number(3.4), 1);
-    let _ = chunk.write(OpCode::Add.into(), 1);
-    let _ = chunk.write_constant(Value::Number(5.6), 2);
-    let _ = chunk.write(OpCode::Divide.into(), 4);
-    chunk.write(OpCode::Return.into(),2);
-    VM::new(&chunk).run().unwrap();
+    let mode = if args.incremental_gc {
+        GcMode::Incremental
+    } else {
+        GcMode::StopTheWorld
+    };
+    let mut interpreter = Interpreter::with_gc_mode(Box::new(io::stdout()), mode);
+    interpreter.set_gc_stress(args.gc_stress);
 
     if args.filename.is_none() {
-        run_prompt();
+        run_prompt(interpreter);
     } else {
-        run_file(&args.filename.unwrap_or_default());
+        run_file(interpreter, &args.filename.unwrap_or_default());
     }
 }
@@ -0,0 +1,738 @@
+use std::mem;
+
+use crate::object::{hash_string, Obj, ObjKind, ObjRef, ObjString, Upvalue};
+use crate::table::Table;
+use crate::value::Value;
+
+/// Once a collection finishes, the next one is scheduled for when the heap has grown by this factor.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// How many bytes may be allocated before the first collection.
+const GC_FIRST_THRESHOLD: usize = 1024 * 1024;
+
+/// How many gray objects an incremental collection traces, or how many objects it sweeps, at
+/// each step.
+const GC_STEP_WORK: usize = 64;
+
+/// How the heap reclaims memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GcMode {
+    /// Mark and sweep the whole heap in one go whenever it has grown enough.
+    #[default]
+    StopTheWorld,
+    /// Spread marking and sweeping over many steps, interleaved with the program, to keep
+    /// pauses short.
+    ///
+    /// Objects are white (unmarked), gray (marked, with references still to trace) or black
+    /// (marked and traced). Black objects must never refer to white ones, or the white ones would
+    /// be freed while still reachable. So while marking, anything stored into an object is
+    /// shaded gray by `write_barrier`, and new objects are allocated black.
+    Incremental,
+}
+
+/// How far an incremental collection has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Marking,
+    // Objects before `cursor` have been swept: the rest are still to be.
+    Sweeping { cursor: usize },
+}
+
+/// Anything outside the heap holding references to objects which must survive a collection:
+/// the VM's stack and globals, or the functions still being compiled.
+pub trait Roots {
+    fn mark_roots(&self, heap: &mut Heap);
+
+    /// Marks the roots which can change without going through `Heap::write_barrier`. An
+    /// incremental collection marks these again once it has traced everything else, to catch
+    /// what was put in them since marking began. By default, that is all of them.
+    fn mark_unbarriered_roots(&self, heap: &mut Heap) {
+        self.mark_roots(heap);
+    }
+}
+
+/// Nothing outside the heap refers to it, e.g. when compiling without a VM.
+impl Roots for () {
+    fn mark_roots(&self, _heap: &mut Heap) {}
+}
+
+/// An object about to be allocated may refer to others which nothing else does yet, such as
+/// the upvalues of a new closure.
+impl Roots for ObjKind {
+    fn mark_roots(&self, heap: &mut Heap) {
+        heap.mark_references(self);
+    }
+}
+
+/// Roots from several places at once.
+impl<const N: usize> Roots for [&dyn Roots; N] {
+    fn mark_roots(&self, heap: &mut Heap) {
+        for roots in self {
+            roots.mark_roots(heap);
+        }
+    }
+
+    fn mark_unbarriered_roots(&self, heap: &mut Heap) {
+        for roots in self {
+            roots.mark_unbarriered_roots(heap);
+        }
+    }
+}
+
+/// Owns every object allocated while running a program, and frees them once unreachable.
+///
+/// The book threads all objects onto an intrusive linked list so they can be swept. We keep
+/// the handles in a `Vec` instead.
+///
+/// Every allocation is handed the roots, but only collects under stress. Otherwise whoever holds
+/// the roots checks `should_collect` at points where every live object is reachable from them,
+/// and calls `collect`.
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    // Every string ever allocated, used as a set: values are always nil.
+    // The set is weak: strings which are otherwise unreachable are removed from it when collected.
+    strings: Table,
+    // Marked objects whose references have not been traced yet.
+    gray_stack: Vec<ObjRef>,
+    // an estimate of the memory used by live objects
+    bytes_allocated: usize,
+    next_gc: usize,
+    // Collect before every allocation, and at every safe point after one, to shake out missing roots.
+    stress: bool,
+    allocated_since_gc: bool,
+    mode: GcMode,
+    phase: Phase,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::with_mode(GcMode::default())
+    }
+
+    pub fn with_mode(mode: GcMode) -> Self {
+        Self {
+            objects: Vec::new(),
+            strings: Table::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_FIRST_THRESHOLD,
+            stress: false,
+            allocated_since_gc: false,
+            mode,
+            phase: Phase::Idle,
+        }
+    }
+
+    pub fn mode(&self) -> GcMode {
+        self.mode
+    }
+
+    /// Whether an incremental collection is part way through marking.
+    pub fn is_marking(&self) -> bool {
+        self.phase == Phase::Marking
+    }
+
+    /// Whether an incremental collection is in progress, marking or sweeping.
+    pub fn is_collecting(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+
+    /// Makes every allocation collect first, to shake out objects which are still in use but
+    /// not reachable from the roots.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    /// Moves `kind` onto the heap. `roots` must mark every object in use, as under stress this
+    /// collects first; the objects `kind` refers to are kept alive too.
+    pub(crate) fn alloc(&mut self, kind: ObjKind, roots: &dyn Roots) -> ObjRef {
+        if self.stress {
+            let roots: [&dyn Roots; 2] = [roots, &kind];
+            self.collect(&roots);
+        }
+        let obj = ObjRef::new(Box::new(Obj::new(kind)));
+        self.bytes_allocated += Self::size_of(&obj);
+        self.allocated_since_gc = true;
+        self.objects.push(obj);
+        match self.phase {
+            Phase::Idle => {}
+            // Allocate black: the object survives this collection, and whatever it refers to
+            // is shaded so that no black object points to a white one.
+            Phase::Marking => {
+                obj.set_marked(true);
+                self.mark_references(&obj.kind);
+            }
+            // Everything it can refer to survives already. The sweep has yet to reach the end
+            // of the list, where the object goes, so it must be marked to be kept.
+            Phase::Sweeping { .. } => obj.set_marked(true),
+        }
+        obj
+    }
+
+    /// Returns the interned string for `chars`, allocating it if needed.
+    pub(crate) fn take_string(&mut self, chars: String, roots: &dyn Roots) -> ObjRef {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return self.resurrect(interned);
+        }
+        self.intern(chars, hash, roots)
+    }
+
+    /// Like `take_string`, but only copies `chars` if it has not been interned yet.
+    pub(crate) fn copy_string(&mut self, chars: &str, roots: &dyn Roots) -> ObjRef {
+        let hash = hash_string(chars);
+        if let Some(interned) = self.strings.find_string(chars, hash) {
+            return self.resurrect(interned);
+        }
+        self.intern(chars.to_string(), hash, roots)
+    }
+
+    /// An interned string found while marking may have been unreachable so far, but is about to
+    /// be used again: shade it, as the intern table is weak and would not keep it alive.
+    fn resurrect(&mut self, string: ObjRef) -> ObjRef {
+        if self.is_marking() {
+            self.mark_object(string);
+        }
+        string
+    }
+
+    fn intern(&mut self, chars: String, hash: u32, roots: &dyn Roots) -> ObjRef {
+        let string = self.alloc(ObjKind::String(ObjString::new(chars, hash)), roots);
+        self.strings.set(string, Value::nil());
+        string
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The estimated number of bytes used by the objects on the heap.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// How large the heap may grow before the next collection.
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
+    }
+
+    /// Whether `collect` has work to do: either enough has been allocated since the last
+    /// collection to warrant another, or an incremental collection should take its next step.
+    pub fn should_collect(&self) -> bool {
+        if self.stress || self.is_collecting() {
+            self.allocated_since_gc
+        } else {
+            self.bytes_allocated > self.next_gc
+        }
+    }
+
+    /// Does the work this heap's mode calls for at a safe point: a whole collection when
+    /// stopping the world, otherwise one bounded step of an incremental collection.
+    pub fn collect(&mut self, roots: &dyn Roots) {
+        match self.mode {
+            GcMode::StopTheWorld => self.collect_garbage(roots),
+            GcMode::Incremental => self.collect_step(roots),
+        }
+    }
+
+    /// Frees every object which cannot be reached from `roots`, finishing off any incremental
+    /// collection in progress.
+    pub fn collect_garbage(&mut self, roots: &dyn Roots) {
+        if self.phase == Phase::Idle {
+            self.phase = Phase::Marking;
+            roots.mark_roots(self);
+        }
+        if self.phase == Phase::Marking {
+            self.finish_marking(roots);
+        }
+        self.sweep(usize::MAX);
+    }
+
+    /// Traces or sweeps a bounded number of objects, starting a collection if none is in
+    /// progress. Once nothing gray is left, marking is finished and sweeping starts.
+    fn collect_step(&mut self, roots: &dyn Roots) {
+        // Keep steps tiny under stress, so the program runs between as many of them as possible.
+        let work = if self.stress { 1 } else { GC_STEP_WORK };
+        match self.phase {
+            Phase::Idle => {
+                self.phase = Phase::Marking;
+                roots.mark_roots(self);
+                self.mark_step(work, roots);
+            }
+            Phase::Marking => self.mark_step(work, roots),
+            Phase::Sweeping { .. } => self.sweep(work),
+        }
+        self.allocated_since_gc = false;
+    }
+
+    fn mark_step(&mut self, work: usize, roots: &dyn Roots) {
+        for _ in 0..work {
+            match self.gray_stack.pop() {
+                Some(obj) => self.blacken_object(obj),
+                None => break,
+            }
+        }
+        if self.gray_stack.is_empty() {
+            self.finish_marking(roots);
+        }
+    }
+
+    /// Marks the roots which are not behind a write barrier once more, and gets ready to sweep.
+    ///
+    /// Whatever the program has put in those roots since marking began must be traced now, in
+    /// one go. This is usually little work, as most of the heap is already black.
+    fn finish_marking(&mut self, roots: &dyn Roots) {
+        roots.mark_unbarriered_roots(self);
+        self.trace_references();
+        // Interned strings are not roots: drop the ones nothing else refers to before they are freed.
+        self.strings.remove_white();
+        self.phase = Phase::Sweeping { cursor: 0 };
+    }
+
+    /// Must be called whenever `value` is stored into a heap object, such as an instance field
+    /// or a closed upvalue. While incrementally marking, the object may already be black, so
+    /// `value` is shaded to keep it from being freed.
+    pub fn write_barrier(&mut self, value: &Value) {
+        if self.is_marking() {
+            self.mark_value(value);
+        }
+    }
+
+    pub(crate) fn mark_object(&mut self, obj: ObjRef) {
+        if obj.is_marked() {
+            return;
+        }
+        obj.set_marked(true);
+        self.gray_stack.push(obj);
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_object(key);
+            self.mark_value(value);
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    /// Marks everything `obj` refers to.
+    fn blacken_object(&mut self, obj: ObjRef) {
+        self.mark_references(&obj.kind);
+    }
+
+    fn mark_references(&mut self, kind: &ObjKind) {
+        match kind {
+            ObjKind::String(_) => {}
+            ObjKind::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for constant in function.chunk.constants() {
+                    self.mark_value(constant);
+                }
+            }
+            ObjKind::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in &closure.upvalues {
+                    self.mark_object(*upvalue);
+                }
+            }
+            ObjKind::Upvalue(upvalue) => {
+                // Open upvalues point at stack slots, which are roots anyway.
+                if let Upvalue::Closed(value) = &*upvalue.location.borrow() {
+                    self.mark_value(value);
+                }
+            }
+            ObjKind::Class(class) => {
+                self.mark_object(class.name);
+                self.mark_table(&class.methods.borrow());
+            }
+            ObjKind::Instance(instance) => {
+                self.mark_object(instance.class);
+                self.mark_table(&instance.fields.borrow());
+            }
+            ObjKind::BoundMethod(bound) => {
+                self.mark_value(&bound.receiver);
+                self.mark_object(bound.method);
+            }
+        }
+    }
+
+    /// Sweeps up to `work` more objects: frees the unmarked ones, and clears the marks of the
+    /// survivors for next time. Once the whole heap is swept, the collection is finished.
+    fn sweep(&mut self, work: usize) {
+        let Phase::Sweeping { mut cursor } = self.phase else {
+            return;
+        };
+        for _ in 0..work {
+            let Some(&obj) = self.objects.get(cursor) else {
+                break;
+            };
+            if obj.is_marked() {
+                obj.set_marked(false);
+                cursor += 1;
+                continue;
+            }
+            // The last object takes its place, to be swept next.
+            self.objects.swap_remove(cursor);
+            self.bytes_allocated -= Self::size_of(&obj);
+            // Safety: the object is unreachable, so nothing can use the handle after this point.
+            unsafe { obj.free() };
+        }
+        if cursor < self.objects.len() {
+            self.phase = Phase::Sweeping { cursor };
+        } else {
+            self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_FIRST_THRESHOLD);
+            self.allocated_since_gc = false;
+            self.phase = Phase::Idle;
+        }
+    }
+
+    /// A rough count of the bytes an object owns, used to decide when to collect.
+    ///
+    /// Only parts which cannot change after allocation are counted, so the estimate for an object
+    /// is the same when it is freed as when it was allocated. Tables of methods and fields grow
+    /// later, so they are left out.
+    fn size_of(obj: &ObjRef) -> usize {
+        let owned = match &obj.kind {
+            ObjKind::String(string) => string.chars.capacity(),
+            ObjKind::Function(function) => {
+                function.chunk.len() * (1 + mem::size_of::<usize>())
+                    + mem::size_of_val(function.chunk.constants())
+            }
+            ObjKind::Closure(closure) => mem::size_of_val(closure.upvalues.as_slice()),
+            ObjKind::Upvalue(_)
+            | ObjKind::Class(_)
+            | ObjKind::Instance(_)
+            | ObjKind::BoundMethod(_) => 0,
+        };
+        mem::size_of::<Obj>() + owned
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for obj in self.objects.drain(..) {
+            // Safety: the heap is going away, so no handle can be used after this point.
+            unsafe { obj.free() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{ObjClass, ObjClosure, ObjFunction, ObjInstance};
+
+    #[test]
+    fn test_alloc_strings() {
+        let mut heap = Heap::new();
+        let a = heap.copy_string("hello", &());
+        let b = heap.take_string("hello".to_string(), &());
+        assert_eq!(heap.len(), 1);
+        assert_eq!(a.as_string().unwrap().chars, "hello");
+        assert_eq!(a, b);
+        assert_eq!(a.to_string(), "hello");
+    }
+
+    #[test]
+    fn test_interned_strings_are_identical() {
+        let mut heap = Heap::new();
+        let a = heap.copy_string("con", &());
+        let b = heap.copy_string("cat", &());
+        let concat = heap.take_string(format!("{}{}", a, b), &());
+        let literal = heap.copy_string("concat", &());
+        assert_eq!(heap.len(), 3);
+        assert_eq!(concat, literal);
+        assert_ne!(a, b);
+    }
+
+    /// Marks a fixed set of objects, standing in for the VM.
+    struct TestRoots(Vec<ObjRef>);
+
+    impl Roots for TestRoots {
+        fn mark_roots(&self, heap: &mut Heap) {
+            for obj in &self.0 {
+                heap.mark_object(*obj);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut heap = Heap::new();
+        let kept = heap.copy_string("kept", &());
+        heap.copy_string("garbage", &());
+        heap.take_string("more garbage".to_string(), &());
+        assert_eq!(heap.len(), 3);
+
+        heap.collect_garbage(&TestRoots(vec![kept]));
+        assert_eq!(heap.len(), 1);
+        assert!(!kept.is_marked());
+        // The intern table let go of the freed strings, so they are allocated afresh...
+        heap.copy_string("garbage", &());
+        assert_eq!(heap.len(), 2);
+        // ...while surviving strings are still interned.
+        assert_eq!(heap.copy_string("kept", &()), kept);
+        assert_eq!(heap.len(), 2);
+
+        heap.collect_garbage(&());
+        assert!(heap.is_empty());
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+
+    #[test]
+    fn test_collect_traces_references() {
+        let mut heap = Heap::new();
+        let name = heap.copy_string("f", &());
+        let constant = heap.copy_string("constant", &());
+        let mut function = ObjFunction::new(Some(name));
+        function.chunk.add_constant(Value::obj(constant));
+        let function = heap.alloc(ObjKind::Function(function), &());
+        let closure = heap.alloc(ObjKind::Closure(ObjClosure::new(function, Vec::new())), &());
+
+        let class_name = heap.copy_string("A", &());
+        let class = heap.alloc(ObjKind::Class(ObjClass::new(class_name)), &());
+        let instance = heap.alloc(ObjKind::Instance(ObjInstance::new(class)), &());
+        let field = heap.copy_string("field", &());
+        if let Some(instance) = instance.as_instance() {
+            instance.fields.borrow_mut().set(field, Value::obj(closure));
+        }
+        heap.copy_string("garbage", &());
+        assert_eq!(heap.len(), 9);
+
+        // Everything but the garbage is reachable from the instance.
+        heap.collect_garbage(&TestRoots(vec![instance]));
+        assert_eq!(heap.len(), 8);
+        assert_eq!(constant.to_string(), "constant");
+
+        // A cycle (a method closure stored in its own class) is still freed once unreachable.
+        if let Some(class) = class.as_class() {
+            class.methods.borrow_mut().set(name, Value::obj(closure));
+        }
+        heap.collect_garbage(&());
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_collection_schedule() {
+        let mut heap = Heap::new();
+        assert!(!heap.should_collect());
+        let mut kept = Vec::new();
+        while !heap.should_collect() {
+            kept.push(heap.take_string(format!("{:0>1000}", kept.len()), &()));
+        }
+        assert!(heap.bytes_allocated() > GC_FIRST_THRESHOLD);
+
+        // Everything survives, so the next collection waits until the heap has doubled.
+        heap.collect_garbage(&TestRoots(kept.clone()));
+        assert_eq!(heap.len(), kept.len());
+        assert_eq!(heap.next_gc(), heap.bytes_allocated() * GC_HEAP_GROW_FACTOR);
+        assert!(!heap.should_collect());
+
+        heap.collect_garbage(&());
+        assert_eq!(heap.next_gc(), GC_FIRST_THRESHOLD);
+    }
+
+    #[test]
+    fn test_stress_collects_after_every_allocation() {
+        let mut heap = Heap::new();
+        heap.set_stress(true);
+        assert!(!heap.should_collect());
+        heap.copy_string("a", &());
+        assert!(heap.should_collect());
+        heap.collect_garbage(&());
+        assert!(!heap.should_collect());
+        // Finding an already-interned string allocates nothing.
+        let a = heap.copy_string("a", &());
+        heap.collect_garbage(&TestRoots(vec![a]));
+        heap.copy_string("a", &());
+        assert!(!heap.should_collect());
+    }
+
+    #[test]
+    fn test_stress_collects_on_every_allocation() {
+        let mut heap = Heap::new();
+        heap.set_stress(true);
+        heap.copy_string("garbage", &());
+        let kept = heap.copy_string("kept", &());
+        assert_eq!(heap.len(), 1);
+        heap.copy_string("more garbage", &TestRoots(vec![kept]));
+        assert_eq!(heap.len(), 2);
+
+        // What the new object refers to survives, even if nothing else does yet.
+        let function = heap.alloc(ObjKind::Function(ObjFunction::new(Some(kept))), &());
+        let closure = ObjClosure::new(function, Vec::new());
+        let closure = heap.alloc(ObjKind::Closure(closure), &());
+        assert_eq!(heap.len(), 3);
+        heap.collect_garbage(&TestRoots(vec![closure]));
+        assert_eq!(heap.len(), 3);
+        assert_eq!(kept.to_string(), "kept");
+    }
+
+    /// Allocates an instance with enough fields that marking from it takes more than one step.
+    fn instance_with_many_fields(heap: &mut Heap) -> ObjRef {
+        let class_name = heap.copy_string("A", &());
+        let class = heap.alloc(ObjKind::Class(ObjClass::new(class_name)), &());
+        let instance = heap.alloc(ObjKind::Instance(ObjInstance::new(class)), &());
+        for i in 0..GC_STEP_WORK * 2 {
+            let name = heap.take_string(format!("field{}", i), &());
+            let value = heap.take_string(format!("value{}", i), &());
+            if let Some(instance) = instance.as_instance() {
+                instance.fields.borrow_mut().set(name, Value::obj(value));
+            }
+        }
+        instance
+    }
+
+    #[test]
+    fn test_incremental_collection_takes_steps() {
+        let mut heap = Heap::with_mode(GcMode::Incremental);
+        let instance = instance_with_many_fields(&mut heap);
+        heap.copy_string("garbage", &());
+        let live = heap.len() - 1;
+        let roots = TestRoots(vec![instance]);
+
+        heap.collect(&roots);
+        assert!(heap.is_marking());
+        assert_eq!(heap.len(), live + 1);
+        while heap.is_collecting() {
+            heap.collect(&roots);
+        }
+        assert_eq!(heap.len(), live);
+        assert!(!instance.is_marked());
+    }
+
+    #[test]
+    fn test_incremental_sweep_takes_steps() {
+        let mut heap = Heap::with_mode(GcMode::Incremental);
+        let kept = heap.copy_string("kept", &());
+        for i in 0..GC_STEP_WORK * 3 {
+            heap.take_string(format!("garbage{}", i), &());
+        }
+        let roots = TestRoots(vec![kept]);
+        while heap.is_marking() || !heap.is_collecting() {
+            heap.collect(&roots);
+        }
+
+        // Each step frees no more than its share of the garbage.
+        let mut lengths = vec![heap.len()];
+        while heap.is_collecting() {
+            heap.collect(&roots);
+            lengths.push(heap.len());
+        }
+        // 3 * GC_STEP_WORK + 1 objects to sweep take four steps.
+        assert_eq!(lengths.len(), 5);
+        assert!(lengths
+            .windows(2)
+            .all(|pair| pair[0] - pair[1] <= GC_STEP_WORK));
+        assert_eq!(heap.len(), 1);
+        assert!(!kept.is_marked());
+        assert_eq!(heap.bytes_allocated(), Heap::size_of(&kept));
+    }
+
+    #[test]
+    fn test_allocation_while_sweeping_survives() {
+        let mut heap = Heap::with_mode(GcMode::Incremental);
+        for i in 0..GC_STEP_WORK * 2 {
+            heap.take_string(format!("garbage{}", i), &());
+        }
+        while heap.is_marking() || !heap.is_collecting() {
+            heap.collect(&());
+        }
+        heap.collect(&());
+        assert!(heap.is_collecting() && !heap.is_marking());
+
+        // Nothing refers to it, but it was allocated after marking finished.
+        let new = heap.copy_string("new", &());
+        while heap.is_collecting() {
+            heap.collect(&());
+        }
+        assert_eq!(heap.len(), 1);
+        assert_eq!(new.to_string(), "new");
+        assert!(!new.is_marked());
+        assert_eq!(heap.copy_string("new", &()), new);
+    }
+
+    #[test]
+    fn test_incremental_allocation_survives() {
+        let mut heap = Heap::with_mode(GcMode::Incremental);
+        let instance = instance_with_many_fields(&mut heap);
+        let roots = TestRoots(vec![instance]);
+        heap.collect(&roots);
+        assert!(heap.is_marking());
+
+        // Allocated black while marking: survives even though nothing refers to it...
+        let new = heap.copy_string("new", &());
+        assert!(new.is_marked());
+        heap.collect_garbage(&roots);
+        assert_eq!(new.to_string(), "new");
+        // ...until the next collection.
+        let live = heap.len();
+        heap.collect_garbage(&roots);
+        assert_eq!(heap.len(), live - 1);
+    }
+
+    #[test]
+    fn test_write_barrier_keeps_stored_values() {
+        let mut heap = Heap::with_mode(GcMode::Incremental);
+        let instance = instance_with_many_fields(&mut heap);
+        let stored = heap.copy_string("stored", &());
+        let roots = TestRoots(vec![instance]);
+        heap.collect(&roots);
+        assert!(heap.is_marking());
+        // The instance was traced by the first step, and `stored` is still white.
+        assert!(instance.is_marked() && !stored.is_marked());
+
+        let field = heap.copy_string("late", &());
+        if let Some(instance) = instance.as_instance() {
+            instance.fields.borrow_mut().set(field, Value::obj(stored));
+        }
+        heap.write_barrier(&Value::obj(stored));
+        while heap.is_marking() {
+            heap.collect(&roots);
+        }
+        assert_eq!(stored.to_string(), "stored");
+        assert_eq!(heap.copy_string("stored", &()), stored);
+    }
+
+    #[test]
+    fn test_incremental_resurrects_interned_strings() {
+        let mut heap = Heap::with_mode(GcMode::Incremental);
+        let instance = instance_with_many_fields(&mut heap);
+        let unreachable = heap.copy_string("unreachable", &());
+        let roots = TestRoots(vec![instance]);
+        heap.collect(&roots);
+        assert!(heap.is_marking());
+
+        // Looking the string up again while marking hands it back to the program, so it must
+        // survive even though it was unreachable when marking started.
+        let found = heap.copy_string("unreachable", &());
+        assert_eq!(found, unreachable);
+        // The caller has not stored it anywhere yet when the collection finishes.
+        heap.collect_garbage(&roots);
+        assert_eq!(found.to_string(), "unreachable");
+        assert_eq!(heap.copy_string("unreachable", &()), unreachable);
+    }
+}
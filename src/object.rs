@@ -0,0 +1,330 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use crate::chunk::Chunk;
+use crate::memory::Heap;
+use crate::table::Table;
+use crate::value::Value;
+
+/// A handle to an object allocated on the VM heap.
+///
+/// Objects are owned by the `Heap` that allocated them: a handle must not be
+/// dereferenced after its heap has been dropped, or after a collection has freed the object.
+/// Dereferencing is safe because only the crate can hold handles, and it keeps every handle it
+/// uses reachable from the roots it passes to the collector.
+#[derive(Clone, Copy)]
+pub(crate) struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    pub(crate) fn new(obj: Box<Obj>) -> Self {
+        Self(NonNull::from(Box::leak(obj)))
+    }
+
+    /// Frees the underlying object.
+    ///
+    /// # Safety
+    /// The handle (and every copy of it) must never be used again.
+    pub(crate) unsafe fn free(self) {
+        drop(Box::from_raw(self.0.as_ptr()));
+    }
+
+    /// Like `deref`, but borrows from the heap rather than the handle, for handles decoded on the
+    /// fly.
+    pub(crate) fn get(self, _heap: &Heap) -> &Obj {
+        // Safety: as for `deref`, the heap keeps the object alive, not the handle.
+        unsafe { self.0.as_ref() }
+    }
+
+    /// The address of the object, for packing into a NaN-boxed value.
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) fn to_bits(self) -> u64 {
+        self.0.as_ptr() as usize as u64
+    }
+
+    /// # Safety
+    /// `bits` must have come from `to_bits` on a handle to an object which is still alive.
+    #[cfg(feature = "nan-boxing")]
+    pub(crate) unsafe fn from_bits(bits: u64) -> Self {
+        Self(NonNull::new_unchecked(bits as usize as *mut Obj))
+    }
+
+    pub(crate) fn as_string(&self) -> Option<&ObjString> {
+        match &self.kind {
+            ObjKind::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_function(&self) -> Option<&ObjFunction> {
+        match &self.kind {
+            ObjKind::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_closure(&self) -> Option<&ObjClosure> {
+        match &self.kind {
+            ObjKind::Closure(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_upvalue(&self) -> Option<&ObjUpvalue> {
+        match &self.kind {
+            ObjKind::Upvalue(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_class(&self) -> Option<&ObjClass> {
+        match &self.kind {
+            ObjKind::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_instance(&self) -> Option<&ObjInstance> {
+        match &self.kind {
+            ObjKind::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bound_method(&self) -> Option<&ObjBoundMethod> {
+        match &self.kind {
+            ObjKind::BoundMethod(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_string(&self) -> bool {
+        self.as_string().is_some()
+    }
+
+    /// Whether the garbage collector has found the object reachable during the current collection.
+    pub(crate) fn is_marked(&self) -> bool {
+        self.is_marked.get()
+    }
+
+    pub(crate) fn set_marked(&self, marked: bool) {
+        self.is_marked.set(marked);
+    }
+}
+
+impl Deref for ObjRef {
+    type Target = Obj;
+
+    fn deref(&self) -> &Obj {
+        // Safety: the heap keeps every object alive for as long as handles to it exist.
+        unsafe { self.0.as_ref() }
+    }
+}
+
+/// Strings are interned, so identity is equality for every kind of object.
+impl PartialEq for ObjRef {
+    fn eq(&self, other: &ObjRef) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for ObjRef {}
+
+impl Hash for ObjRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjRef({})", **self)
+    }
+}
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
+
+/// A heap-allocated Lox object.
+pub struct Obj {
+    pub kind: ObjKind,
+    is_marked: Cell<bool>,
+}
+
+impl Obj {
+    pub fn new(kind: ObjKind) -> Self {
+        Self {
+            kind,
+            is_marked: Cell::new(false),
+        }
+    }
+}
+
+pub enum ObjKind {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(s) => write!(f, "{}", s.chars),
+            ObjKind::Function(function) => write!(f, "{}", function),
+            ObjKind::Closure(closure) => write!(f, "{}", *closure.function),
+            ObjKind::Upvalue(_) => write!(f, "upvalue"),
+            ObjKind::Class(class) => write!(f, "{}", class.name),
+            ObjKind::Instance(instance) => write!(f, "{} instance", instance.class),
+            ObjKind::BoundMethod(bound) => write!(f, "{}", bound.method),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+impl ObjString {
+    pub fn new(chars: String, hash: u32) -> Self {
+        Self { chars, hash }
+    }
+}
+
+/// 32-bit FNV-1a, the hash function used by clox.
+pub fn hash_string(chars: &str) -> u32 {
+    chars.bytes().fold(2166136261u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(16777619)
+    })
+}
+
+/// A compiled function: its bytecode plus what's needed to call it.
+#[derive(Debug, Default)]
+pub struct ObjFunction {
+    pub arity: usize,
+    // how many variables from enclosing functions the function captures
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // `None` for the implicit function wrapping top-level code
+    pub(crate) name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub(crate) fn new(name: Option<ObjRef>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// A function together with the variables it captured from its enclosing functions.
+///
+/// Every function is wrapped in a closure at runtime, even one which captures nothing.
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub(crate) function: ObjRef,
+    pub(crate) upvalues: Vec<ObjRef>,
+}
+
+impl ObjClosure {
+    pub(crate) fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+/// Where a captured variable lives.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upvalue {
+    // still on the VM stack, at this slot
+    Open(usize),
+    // moved off the stack once the variable went out of scope
+    Closed(Value),
+}
+
+/// A variable captured by a closure. Every closure capturing the same variable shares one of these,
+/// so assignments made through one are seen by all of them.
+#[derive(Debug)]
+pub struct ObjUpvalue {
+    pub location: RefCell<Upvalue>,
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> Self {
+        Self {
+            location: RefCell::new(Upvalue::Open(slot)),
+        }
+    }
+
+    /// The stack slot of the variable, while it is still on the stack.
+    pub fn open_slot(&self) -> Option<usize> {
+        match *self.location.borrow() {
+            Upvalue::Open(slot) => Some(slot),
+            Upvalue::Closed(_) => None,
+        }
+    }
+}
+
+pub struct ObjClass {
+    pub(crate) name: ObjRef,
+    // method names to the closures implementing them
+    pub methods: RefCell<Table>,
+}
+
+impl ObjClass {
+    pub(crate) fn new(name: ObjRef) -> Self {
+        Self {
+            name,
+            methods: RefCell::new(Table::new()),
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub(crate) class: ObjRef,
+    pub fields: RefCell<Table>,
+}
+
+impl ObjInstance {
+    pub(crate) fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: RefCell::new(Table::new()),
+        }
+    }
+}
+
+/// A method closure paired with the instance it was accessed on, so that calling it later
+/// still binds `this` to that instance.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub(crate) method: ObjRef,
+}
+
+impl ObjBoundMethod {
+    pub(crate) fn new(receiver: Value, method: ObjRef) -> Self {
+        Self { receiver, method }
+    }
+}
@@ -11,8 +11,53 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    CloseUpvalue,
+    Inherit,
+    ToString,
     // takes 1 operand
     Constant,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    Call,
+    GetUpvalue,
+    SetUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    GetSuper,
+    // takes 1 operand, followed by a pair of bytes per upvalue of the function it refers to
+    Closure,
+    // takes 2 operands
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Invoke,
+    SuperInvoke,
+    // takes 3 operands: the long forms of the instructions indexing the constant pool above
+    ConstantLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    GetSuperLong,
+    // takes 3 operands, followed by a pair of bytes per upvalue of the function it refers to
+    ClosureLong,
 }
 
 impl OpCode {
@@ -24,7 +69,48 @@ impl OpCode {
             OpCode::Subtract => "OP_SUBTRACT",
             OpCode::Multiply => "OP_MULTIPLY",
             OpCode::Divide => "OP_DIVIDE",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::Less => "OP_LESS",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Pop => "OP_POP",
+            OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE",
             OpCode::Constant => "OP_CONSTANT",
+            OpCode::ConstantLong => "OP_CONSTANT_LONG",
+            OpCode::DefineGlobal => "OP_DEFINE_GLOBAL",
+            OpCode::GetGlobal => "OP_GET_GLOBAL",
+            OpCode::SetGlobal => "OP_SET_GLOBAL",
+            OpCode::GetLocal => "OP_GET_LOCAL",
+            OpCode::SetLocal => "OP_SET_LOCAL",
+            OpCode::Call => "OP_CALL",
+            OpCode::GetUpvalue => "OP_GET_UPVALUE",
+            OpCode::SetUpvalue => "OP_SET_UPVALUE",
+            OpCode::Closure => "OP_CLOSURE",
+            OpCode::Class => "OP_CLASS",
+            OpCode::GetProperty => "OP_GET_PROPERTY",
+            OpCode::SetProperty => "OP_SET_PROPERTY",
+            OpCode::Method => "OP_METHOD",
+            OpCode::Invoke => "OP_INVOKE",
+            OpCode::Inherit => "OP_INHERIT",
+            OpCode::ToString => "OP_TO_STRING",
+            OpCode::GetSuper => "OP_GET_SUPER",
+            OpCode::SuperInvoke => "OP_SUPER_INVOKE",
+            OpCode::Jump => "OP_JUMP",
+            OpCode::JumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::Loop => "OP_LOOP",
+            OpCode::DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+            OpCode::GetGlobalLong => "OP_GET_GLOBAL_LONG",
+            OpCode::SetGlobalLong => "OP_SET_GLOBAL_LONG",
+            OpCode::ClassLong => "OP_CLASS_LONG",
+            OpCode::GetPropertyLong => "OP_GET_PROPERTY_LONG",
+            OpCode::SetPropertyLong => "OP_SET_PROPERTY_LONG",
+            OpCode::MethodLong => "OP_METHOD_LONG",
+            OpCode::GetSuperLong => "OP_GET_SUPER_LONG",
+            OpCode::ClosureLong => "OP_CLOSURE_LONG",
         }
     }
 
@@ -33,12 +119,105 @@ impl OpCode {
             OpCode::Return => 0,
             OpCode::Negate => 0,
             OpCode::Constant => 1,
+            OpCode::ConstantLong => 3,
             OpCode::Add => 0,
             OpCode::Subtract => 0,
             OpCode::Multiply => 0,
             OpCode::Divide => 0,
+            OpCode::Nil => 0,
+            OpCode::True => 0,
+            OpCode::False => 0,
+            OpCode::Not => 0,
+            OpCode::Equal => 0,
+            OpCode::Greater => 0,
+            OpCode::Less => 0,
+            OpCode::Print => 0,
+            OpCode::Pop => 0,
+            OpCode::CloseUpvalue => 0,
+            OpCode::DefineGlobal => 1,
+            OpCode::GetGlobal => 1,
+            OpCode::SetGlobal => 1,
+            OpCode::GetLocal => 1,
+            OpCode::SetLocal => 1,
+            OpCode::Call => 1,
+            OpCode::GetUpvalue => 1,
+            OpCode::SetUpvalue => 1,
+            OpCode::Closure => 1,
+            OpCode::Class => 1,
+            OpCode::GetProperty => 1,
+            OpCode::SetProperty => 1,
+            OpCode::Method => 1,
+            OpCode::Invoke => 2,
+            OpCode::Inherit => 0,
+            OpCode::ToString => 0,
+            OpCode::GetSuper => 1,
+            OpCode::SuperInvoke => 2,
+            OpCode::Jump => 2,
+            OpCode::JumpIfFalse => 2,
+            OpCode::Loop => 2,
+            OpCode::DefineGlobalLong => 3,
+            OpCode::GetGlobalLong => 3,
+            OpCode::SetGlobalLong => 3,
+            OpCode::ClassLong => 3,
+            OpCode::GetPropertyLong => 3,
+            OpCode::SetPropertyLong => 3,
+            OpCode::MethodLong => 3,
+            OpCode::GetSuperLong => 3,
+            OpCode::ClosureLong => 3,
         }
     }
+
+    /// The form of an instruction indexing the constant pool which takes a 24-bit index
+    /// rather than a one-byte one, if it has one.
+    pub fn long(&self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction indexes the constant pool with a 24-bit operand.
+    pub fn is_long(&self) -> bool {
+        self.operand_offset() == 3
+    }
+
+    /// Whether the instruction can raise a runtime error, which needs the source code it was
+    /// compiled from to point at.
+    pub fn can_fail(&self) -> bool {
+        matches!(
+            self,
+            OpCode::Negate
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Greater
+                | OpCode::Less
+                | OpCode::GetGlobal
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobal
+                | OpCode::SetGlobalLong
+                | OpCode::Call
+                | OpCode::GetProperty
+                | OpCode::GetPropertyLong
+                | OpCode::SetProperty
+                | OpCode::SetPropertyLong
+                | OpCode::Invoke
+                | OpCode::Inherit
+                | OpCode::GetSuper
+                | OpCode::GetSuperLong
+                | OpCode::SuperInvoke
+        )
+    }
 }
 
 impl fmt::Display for OpCode {
@@ -1,19 +1,42 @@
 use crate::{
-    error::InterpreterError,
+    error::{Diagnostics, InterpreterError, SourceDiagnostic},
     token::{Token, TokenType},
 };
+use miette::SourceSpan;
+use std::borrow::Cow;
 use std::iter::Peekable;
 use std::str::Chars;
 
+/// A `${` in a string whose expression is still being scanned.
+struct Interpolation {
+    // unmatched `{` in the expression so far: the `}` which ends it comes with none left
+    braces: usize,
+    // the line, column and offset of the `$`
+    start: (usize, usize, usize),
+}
+
 pub struct Scanner<'a> {
     // all the source code as a peekable iterator
     source: Peekable<Chars<'a>>,
+    // and as it is, for lexemes to borrow from
+    text: &'a str,
     // tokens: Vec<Token>,
+    // Offsets are in bytes, so that they can index the source and delimit spans.
     start: usize,
     pub current: usize,
     pub line: usize,
+    // characters (not bytes) between the start of the line and `current`
+    column: usize,
+    // where the token being scanned starts
+    start_line: usize,
+    start_column: usize,
     // We're going to immediately turn sourcecode into an iterator
     source_length: usize,
+    // whether the `Eof` token has been produced, after which there are no more tokens
+    finished: bool,
+    // Interpolations in strings can nest, as strings can be written in their expressions:
+    // the innermost is last.
+    interpolations: Vec<Interpolation>,
 }
 
 impl<'a> Scanner<'a> {
@@ -21,11 +44,17 @@ impl<'a> Scanner<'a> {
         let source_length = source.len();
         Self {
             source: source.chars().peekable(),
+            text: source,
             // tokens: vec![],
             start: 0,
             current: 0,
             line: 1,
+            column: 0,
+            start_line: 1,
+            start_column: 0,
             source_length,
+            finished: false,
+            interpolations: Vec::new(),
         }
     }
     fn is_at_end(&self) -> bool {
@@ -33,8 +62,15 @@ impl<'a> Scanner<'a> {
     }
 
     fn advance(&mut self) -> Option<char> {
-        self.current += 1;
-        self.source.next()
+        let c = self.source.next()?;
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
     }
 
     fn token_match(&mut self, expected: char) -> bool {
@@ -58,152 +94,285 @@ impl<'a> Scanner<'a> {
         c.is_alphabetic() || c == &'_'
     }
 
-    fn scan_identifier(&mut self, start_char: char) -> Result<Option<Token>, InterpreterError> {
-        let mut chars = vec![start_char];
-        while self.source.peek().map_or(false, Self::is_alpha) {
-            chars.push(self.advance().unwrap_or_default());
-        }
-        let lexeme = chars.into_iter().collect::<String>();
-        let token_type: TokenType =
-            TokenType::scan_for_keyword(&lexeme[..]).unwrap_or(TokenType::Identifier);
-        Ok(Some(Token::new(
+    /// The bytes scanned since the start of the current token.
+    fn span(&self) -> SourceSpan {
+        SourceSpan::new(self.start.into(), self.current - self.start)
+    }
+
+    /// The source of the current token, from `offset` bytes after its start up to `trim` bytes
+    /// before where scanning has got to.
+    fn lexeme(&self, offset: usize, trim: usize) -> Option<Cow<'a, str>> {
+        Some(Cow::Borrowed(
+            &self.text[self.start + offset..self.current - trim],
+        ))
+    }
+
+    fn make_token(&self, token_type: TokenType, lexeme: Option<Cow<'a, str>>) -> Token<'a> {
+        Token::new(
             token_type,
-            Some(lexeme),
-            self.line,
-            self.start,
-        )))
+            lexeme,
+            self.start_line,
+            self.start_column + 1,
+            self.span(),
+        )
+    }
+
+    /// A token standing in for some source code which could not be scanned. It carries the
+    /// message as its lexeme, and scanning carries on after it.
+    fn error_token(&self, message: String) -> Token<'a> {
+        self.make_token(TokenType::TokenError, Some(Cow::Owned(message)))
     }
-    fn scan_number(&mut self, start_char: char) -> Result<Option<Token>, InterpreterError> {
-        let mut chars = vec![start_char];
 
+    fn scan_identifier(&mut self) -> Result<Option<Token<'a>>, InterpreterError> {
+        while self
+            .source
+            .peek()
+            .is_some_and(|c| Self::is_alpha(c) || c.is_ascii_digit())
+        {
+            self.advance();
+        }
+        let lexeme = self.lexeme(0, 0);
+        let token_type: TokenType = lexeme
+            .as_deref()
+            .and_then(TokenType::scan_for_keyword)
+            .unwrap_or(TokenType::Identifier);
+        Ok(Some(self.make_token(token_type, lexeme)))
+    }
+    fn scan_number(&mut self) -> Result<Option<Token<'a>>, InterpreterError> {
         while Self::is_digit(self.source.peek()) {
-            chars.push(self.advance().unwrap_or_default());
+            self.advance();
         }
         // look for a fractional part
         if self.source.peek() == Some(&'.') {
-            chars.push(self.advance().unwrap_or_default());
+            self.advance();
             while Self::is_digit(self.source.peek()) {
-                chars.push(self.advance().unwrap_or_default());
+                self.advance();
             }
         }
-        Ok(Some(Token::new(
-            TokenType::Number,
-            Some(chars.into_iter().collect::<String>()),
-            self.line,
-            self.start,
-        )))
+        Ok(Some(self.make_token(TokenType::Number, self.lexeme(0, 0))))
     }
 
-    fn scan_string(&mut self) -> Result<Option<Token>, InterpreterError> {
-        let mut chars = vec![];
+    /// Scans a string literal up to its end or the next `${`. If `resumed`, it's the rest of a
+    /// string after an interpolated expression, starting from the `}` which closed that.
+    fn scan_string(&mut self, resumed: bool) -> Result<Option<Token<'a>>, InterpreterError> {
+        // Only built once there's an escape sequence, as the lexeme can't borrow the source then.
+        let mut unescaped: Option<String> = None;
+        // the first malformed escape sequence, reported once the whole string has been scanned
+        let mut error = None;
         while self.source.peek() != Some(&'"') && !self.is_at_end() {
-            if self.source.peek() == Some(&'\n') {
-                self.line += 1;
+            if self.text[self.current..].starts_with("${") {
+                let start = (self.line, self.column, self.current);
+                self.advance();
+                self.advance();
+                self.interpolations.push(Interpolation { braces: 0, start });
+                if error.is_some() {
+                    return Ok(error);
+                }
+                let token_type = if resumed {
+                    TokenType::InterpolationSegment
+                } else {
+                    TokenType::InterpolationStart
+                };
+                let lexeme = match unescaped {
+                    Some(chars) => Some(Cow::Owned(chars)),
+                    // chop the quote (or brace) and the `${`
+                    None => self.lexeme(1, 2),
+                };
+                return Ok(Some(self.make_token(token_type, lexeme)));
+            }
+            if self.source.peek() == Some(&'\\') {
+                let chars = unescaped
+                    .get_or_insert_with(|| self.text[self.start + 1..self.current].to_string());
+                match self.scan_escape() {
+                    Ok(c) => chars.push(c),
+                    Err(token) => {
+                        error.get_or_insert(token);
+                    }
+                }
+            } else {
+                let c = self.advance().unwrap_or_default();
+                if let Some(chars) = &mut unescaped {
+                    chars.push(c);
+                }
             }
-            chars.push(self.advance().unwrap_or_default());
         }
         // unterminated string
         if self.is_at_end() {
-            return Err(InterpreterError::ScannerError(None));
+            return Ok(Some(self.error_token("Unterminated string.".to_string())));
         }
-        // chop the closing "
         self.advance();
-        Ok(Some(Token::new(
-            TokenType::String,
-            Some(chars.into_iter().collect::<String>()),
-            self.line,
-            self.start,
-        )))
+        if error.is_some() {
+            return Ok(error);
+        }
+        let token_type = if resumed {
+            TokenType::InterpolationEnd
+        } else {
+            TokenType::String
+        };
+        let lexeme = match unescaped {
+            Some(chars) => Some(Cow::Owned(chars)),
+            // chop the quotes
+            None => self.lexeme(1, 1),
+        };
+        Ok(Some(self.make_token(token_type, lexeme)))
     }
 
-    fn scan_token(&mut self) -> Result<Option<Token>, InterpreterError> {
+    /// Scans an escape sequence in a string, returning the character it stands for, or an error
+    /// token pointing at what's wrong with it.
+    fn scan_escape(&mut self) -> Result<char, Token<'a>> {
+        let start = (self.line, self.column, self.current);
+        // the backslash
+        self.advance();
+        // At the end of the source the string is unterminated, which is reported instead.
+        let Some(c) = self.advance() else {
+            return Ok('\\');
+        };
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '\\' => Ok('\\'),
+            '"' => Ok('"'),
+            '$' => Ok('$'),
+            '0' => Ok('\0'),
+            'u' => self.scan_unicode_escape(start),
+            // A raw line break would split the message across lines, so name it instead.
+            '\n' | '\r' => Err(self.escape_error(
+                "Unknown escape sequence: line break after '\\'.".to_string(),
+                start,
+            )),
+            c => Err(self.escape_error(format!("Unknown escape sequence '\\{}'.", c), start)),
+        }
+    }
+
+    /// Scans the rest of a `\u{XXXX}` escape, once `start` has been scanned up to the `u`.
+    fn scan_unicode_escape(&mut self, start: (usize, usize, usize)) -> Result<char, Token<'a>> {
+        if !self.token_match('{') {
+            return Err(self.escape_error("Expect '{' after '\\u'.".to_string(), start));
+        }
+        let digits_start = self.current;
+        while let Some(&c) = self.source.peek() {
+            if c == '}' {
+                break;
+            }
+            if !c.is_ascii_hexdigit() {
+                // Point at the character alone, and leave it be: it may be the closing quote.
+                let span = SourceSpan::new(self.current.into(), c.len_utf8());
+                return Err(Token::new(
+                    TokenType::TokenError,
+                    Some(Cow::Owned(
+                        "Expect hex digit or '}' in Unicode escape.".to_string(),
+                    )),
+                    self.line,
+                    self.column + 1,
+                    span,
+                ));
+            }
+            self.advance();
+        }
+        let digits = &self.text[digits_start..self.current];
+        if !self.token_match('}') {
+            // the string is unterminated, which is reported instead
+            return Ok(char::REPLACEMENT_CHARACTER);
+        }
+        if digits.is_empty() {
+            return Err(
+                self.escape_error("Expect hex digits in Unicode escape.".to_string(), start)
+            );
+        }
+        if digits.len() > 6 {
+            return Err(self.escape_error(
+                "Unicode escape can have at most 6 hex digits.".to_string(),
+                start,
+            ));
+        }
+        u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| {
+                self.escape_error(
+                    format!("'{}' is not a Unicode scalar value.", digits),
+                    start,
+                )
+            })
+    }
+
+    /// An error for the escape sequence from `start` (the line, column and offset of its
+    /// backslash) up to where scanning has got to.
+    fn escape_error(&self, message: String, start: (usize, usize, usize)) -> Token<'a> {
+        let (line, column, offset) = start;
+        Token::new(
+            TokenType::TokenError,
+            Some(Cow::Owned(message)),
+            line,
+            column + 1,
+            SourceSpan::new(offset.into(), self.current - offset),
+        )
+    }
+
+    fn scan_token(&mut self) -> Result<Option<Token<'a>>, InterpreterError> {
         let _char = self.advance();
         let loxchar = match _char {
             Some(loxchar) => loxchar,
-            None => return Err(InterpreterError::ScannerError(None)),
+            None => {
+                return Ok(Some(
+                    self.error_token("Unexpected end of input.".to_string()),
+                ))
+            }
         };
-        let next_token = match loxchar {
-            '(' => Some(Token::new(
-                TokenType::LeftParen,
-                None,
-                self.line,
-                self.start,
-            )),
-            ')' => Some(Token::new(
-                TokenType::RightParen,
-                None,
-                self.line,
-                self.start,
-            )),
-            '{' => Some(Token::new(
-                TokenType::LeftBrace,
-                None,
-                self.line,
-                self.start,
-            )),
-            '}' => Some(Token::new(
-                TokenType::RightBrace,
-                None,
-                self.line,
-                self.start,
-            )),
-            ',' => Some(Token::new(TokenType::Comma, None, self.line, self.start)),
-            '.' => Some(Token::new(TokenType::Dot, None, self.line, self.start)),
-            '-' => Some(Token::new(TokenType::Minus, None, self.line, self.start)),
-            '+' => Some(Token::new(TokenType::Plus, None, self.line, self.start)),
-            ';' => Some(Token::new(
-                TokenType::Semicolon,
-                None,
-                self.line,
-                self.start,
-            )),
-            '*' => Some(Token::new(TokenType::Star, None, self.line, self.start)),
+        let token_type = match loxchar {
+            '(' => TokenType::LeftParen,
+            ')' => TokenType::RightParen,
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.braces += 1;
+                }
+                TokenType::LeftBrace
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(interpolation) if interpolation.braces == 0 => {
+                    // the end of an interpolated expression: back to the string around it
+                    self.interpolations.pop();
+                    return self.scan_string(true);
+                }
+                Some(interpolation) => {
+                    interpolation.braces -= 1;
+                    TokenType::RightBrace
+                }
+                None => TokenType::RightBrace,
+            },
+            ',' => TokenType::Comma,
+            '.' => TokenType::Dot,
+            '-' => TokenType::Minus,
+            '+' => TokenType::Plus,
+            ';' => TokenType::Semicolon,
+            '*' => TokenType::Star,
             '!' => {
                 if self.token_match('=') {
-                    Some(Token::new(
-                        TokenType::BangEqual,
-                        None,
-                        self.line,
-                        self.start,
-                    ))
+                    TokenType::BangEqual
                 } else {
-                    Some(Token::new(TokenType::Bang, None, self.line, self.start))
+                    TokenType::Bang
                 }
             }
             '=' => {
                 if self.token_match('=') {
-                    Some(Token::new(
-                        TokenType::EqualEqual,
-                        None,
-                        self.line,
-                        self.start,
-                    ))
+                    TokenType::EqualEqual
                 } else {
-                    Some(Token::new(TokenType::Equal, None, self.line, self.start))
+                    TokenType::Equal
                 }
             }
             '<' => {
                 if self.token_match('=') {
-                    Some(Token::new(
-                        TokenType::LessEqual,
-                        None,
-                        self.line,
-                        self.start,
-                    ))
+                    TokenType::LessEqual
                 } else {
-                    Some(Token::new(TokenType::Less, None, self.line, self.start))
+                    TokenType::Less
                 }
             }
             '>' => {
                 if self.token_match('=') {
-                    Some(Token::new(
-                        TokenType::GreaterEqual,
-                        None,
-                        self.line,
-                        self.start,
-                    ))
+                    TokenType::GreaterEqual
                 } else {
-                    Some(Token::new(TokenType::Greater, None, self.line, self.start))
+                    TokenType::Greater
                 }
             }
             '/' => {
@@ -211,40 +380,94 @@ impl<'a> Scanner<'a> {
                     while self.source.peek() != Some(&'\n') && !self.is_at_end() {
                         self.advance();
                     }
-                    None
+                    return Ok(None);
                 } else {
-                    Some(Token::new(TokenType::Slash, None, self.line, self.start))
+                    TokenType::Slash
                 }
             }
-            ' ' | '\r' | '\t' => None,
-            '\n' => {
-                self.line += 1;
-                None
-            }
-            '"' => self.scan_string()?,
-            '0'..='9' => self.scan_number(loxchar)?,
+            ' ' | '\r' | '\t' | '\n' => return Ok(None),
+            '"' => return self.scan_string(false),
+            '0'..='9' => return self.scan_number(),
             ch => {
                 if Self::is_alpha(&ch) {
-                    self.scan_identifier(loxchar)?
+                    return self.scan_identifier();
                 } else {
-                    return Err(InterpreterError::ScannerError(Some(loxchar.to_string())));
+                    return Ok(Some(
+                        self.error_token(format!("Unexpected character '{}'.", loxchar)),
+                    ));
                 }
             }
         };
-        Ok(next_token)
+        Ok(Some(self.make_token(token_type, self.lexeme(0, 0))))
     }
 
-    pub fn scan_tokens(&mut self) -> Result<Vec<Token>, InterpreterError> {
+    /// Scans the whole source. If any of it could not be scanned, every error is returned
+    /// together instead of the tokens.
+    pub fn scan_tokens(&mut self) -> Result<Vec<Token<'a>>, InterpreterError> {
         let mut tokens = vec![];
-        while !self.is_at_end() {
+        let mut errors = vec![];
+        for token in self {
+            match token {
+                Ok(token) => tokens.push(token),
+                Err(InterpreterError::ScannerError(diagnostics)) => {
+                    errors.extend(diagnostics.errors)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(InterpreterError::ScannerError(Diagnostics::new(
+                "Could not scan the source",
+                errors,
+            )))
+        }
+    }
+}
+
+/// Scans one token at a time, on demand, ending with a single `Eof`. Source which can't be
+/// scanned produces an error in place of a token, after which scanning carries on.
+impl<'a> Iterator for Scanner<'a> {
+    type Item = Result<Token<'a>, InterpreterError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = loop {
+            if self.is_at_end() {
+                if let Some(interpolation) = self.interpolations.pop() {
+                    let (line, column, offset) = interpolation.start;
+                    break Token::new(
+                        TokenType::TokenError,
+                        Some(Cow::Borrowed("Unterminated string interpolation.")),
+                        line,
+                        column + 1,
+                        SourceSpan::new(offset.into(), 2),
+                    );
+                }
+                if self.finished {
+                    return None;
+                }
+                self.finished = true;
+                break Token::end(self.line, self.column + 1, self.current);
+            }
             self.start = self.current;
-            let token = self.scan_token()?;
-            if let Some(token) = token {
-                tokens.push(token);
+            self.start_line = self.line;
+            self.start_column = self.column;
+            match self.scan_token() {
+                Ok(Some(token)) => break token,
+                Ok(None) => {}
+                Err(error) => return Some(Err(error)),
             }
+        };
+        if token.token_type != TokenType::TokenError {
+            return Some(Ok(token));
         }
-        tokens.push(Token::end(self.line, self.start));
-        Ok(tokens)
+        let message = token.lexeme.unwrap_or_default().into_owned();
+        let error = SourceDiagnostic::new(message, "here", token.span);
+        Some(Err(InterpreterError::ScannerError(Diagnostics::new(
+            "Could not scan the source",
+            vec![error],
+        ))))
     }
 }
 
@@ -259,11 +482,12 @@ mod tests {
             "super", "this", "true", "var", "while",
         ];
         for source in sources {
-            let (start, end) = source.split_at(1);
-            let mut scanner = Scanner::new(end);
-            let token = scanner.scan_identifier(start.chars().nth(0).expect("missing chars"));
+            let mut scanner = Scanner::new(source);
+            // scan_token has consumed the first character by the time it calls this
+            scanner.advance();
+            let token = scanner.scan_identifier();
             eprintln!("{:?}", token);
-            assert_eq!(token.is_ok(), true);
+            assert!(token.is_ok());
             let token = token.unwrap();
             assert!(token.is_some());
             let token = token.unwrap();
@@ -293,15 +517,16 @@ mod tests {
             "whilest",
         ];
         for source in sources {
-            let (start, end) = source.split_at(1);
-            let mut scanner = Scanner::new(end);
-            let token = scanner.scan_identifier(start.chars().nth(0).expect("missing chars"));
+            let mut scanner = Scanner::new(source);
+            // scan_token has consumed the first character by the time it calls this
+            scanner.advance();
+            let token = scanner.scan_identifier();
             eprintln!("{:?}", token);
-            assert_eq!(token.is_ok(), true);
+            assert!(token.is_ok());
             let token = token.unwrap();
             assert!(token.is_some());
             let token = token.unwrap();
-            assert!(token.lexeme.is_some());
+            assert_eq!(token.lexeme.as_deref(), Some(source));
             assert!(!token.token_type.is_keyword());
         }
     }
@@ -311,9 +536,10 @@ mod tests {
         let sources: Vec<&str> = vec!["23", "23.45"];
         for source in sources {
             let mut scanner = Scanner::new(source);
-            let token = scanner.scan_number('1');
+            scanner.advance();
+            let token = scanner.scan_number();
             eprintln!("{:?}", token);
-            assert_eq!(token.is_ok(), true);
+            assert!(token.is_ok());
             let token = token.unwrap();
             assert!(token.is_some());
             let token = token.unwrap();
@@ -323,17 +549,20 @@ mod tests {
     }
     #[test]
     fn test_scan_string() {
-        // we assume a string passed to this method will have no opening "
-        let source = "hello world\"";
+        // we assume the opening " has already been consumed
+        let source = "\"hello world\"";
         let mut scanner = Scanner::new(source);
-        let token = scanner.scan_string();
+        scanner.advance();
+        let token = scanner.scan_string(false);
         eprintln!("{:?}", token);
-        assert_eq!(token.is_ok(), true);
+        assert!(token.is_ok());
         let token = token.unwrap();
         assert!(token.is_some());
         let token = token.unwrap();
         assert_eq!(token.token_type, TokenType::String);
-        assert_eq!(token.lexeme, Some("hello world".to_string()));
+        assert_eq!(token.lexeme.as_deref(), Some("hello world"));
+        // borrowed straight from the source
+        assert!(matches!(token.lexeme, Some(Cow::Borrowed(_))));
     }
 
     #[test]
@@ -372,4 +601,277 @@ mod tests {
         assert_eq!(tokens[4].token_type, TokenType::Semicolon);
         assert_eq!(tokens[5].token_type, TokenType::Eof);
     }
+
+    #[test]
+    fn test_identifiers_with_digits() {
+        let source = "var x1 = 3; p.a0 = x1;";
+        let identifiers: Vec<_> = Scanner::new(source)
+            .scan_tokens()
+            .unwrap()
+            .into_iter()
+            .filter(|token| token.token_type == TokenType::Identifier)
+            .map(|token| token.lexeme.unwrap_or_default())
+            .collect();
+        assert_eq!(identifiers, vec!["x1", "p", "a0", "x1"]);
+    }
+
+    /// The source text covered by a token's span.
+    fn spanned<'a>(source: &'a str, token: &Token) -> &'a str {
+        &source[token.span.offset()..token.span.offset() + token.span.len()]
+    }
+
+    #[test]
+    fn test_spans_are_byte_offsets() {
+        let source = "var café = \"naïve\"; // ünïcode\nprint café;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let spanned: Vec<_> = tokens.iter().map(|t| spanned(source, t)).collect();
+        assert_eq!(
+            spanned,
+            vec![
+                "var",
+                "café",
+                "=",
+                "\"naïve\"",
+                ";",
+                "print",
+                "café",
+                ";",
+                ""
+            ]
+        );
+        let positions: Vec<_> = tokens.iter().map(|t| (t.line, t.column)).collect();
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 10),
+                (1, 12),
+                (1, 19),
+                (2, 1),
+                (2, 7),
+                (2, 11),
+                (2, 12)
+            ]
+        );
+        assert_eq!(tokens.last().unwrap().span.offset(), source.len());
+    }
+
+    #[test]
+    fn test_multiline_string_starts_on_its_first_line() {
+        let source = "\"one\ntwo\" x";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        assert_eq!((tokens[0].line, tokens[0].column), (1, 1));
+        assert_eq!(spanned(source, &tokens[0]), "\"one\ntwo\"");
+        assert_eq!((tokens[1].line, tokens[1].column), (2, 6));
+    }
+
+    #[test]
+    fn test_scan_errors_point_at_source() {
+        let Err(InterpreterError::ScannerError(diagnostics)) = Scanner::new("é = @;").scan_tokens()
+        else {
+            panic!("expected a scanner error");
+        };
+        let [diagnostic] = &diagnostics.errors[..] else {
+            panic!("expected one error");
+        };
+        assert_eq!(diagnostic.message, "Unexpected character '@'.");
+        assert_eq!(diagnostic.span, SourceSpan::new(5.into(), 1));
+
+        let Err(InterpreterError::ScannerError(diagnostics)) =
+            Scanner::new("print \"ü").scan_tokens()
+        else {
+            panic!("expected a scanner error");
+        };
+        let [diagnostic] = &diagnostics.errors[..] else {
+            panic!("expected one error");
+        };
+        assert_eq!(diagnostic.message, "Unterminated string.");
+        assert_eq!(diagnostic.span, SourceSpan::new(6.into(), 3));
+    }
+
+    #[test]
+    fn test_scan_collects_every_error() {
+        let mut scanner = Scanner::new("var a = @;\nprint # + 1;\n\"open");
+        let Err(InterpreterError::ScannerError(diagnostics)) = scanner.scan_tokens() else {
+            panic!("expected a scanner error");
+        };
+        let errors: Vec<_> = diagnostics
+            .errors
+            .iter()
+            .map(|error| (error.message.as_str(), error.span.offset()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("Unexpected character '@'.", 8),
+                ("Unexpected character '#'.", 17),
+                ("Unterminated string.", 24),
+            ]
+        );
+
+        // the error tokens take the place of what could not be scanned
+        let mut scanner = Scanner::new("@ + # 1");
+        let mut token_types = vec![];
+        while !scanner.is_at_end() {
+            scanner.start = scanner.current;
+            if let Some(token) = scanner.scan_token().unwrap() {
+                token_types.push(token.token_type);
+            }
+        }
+        assert_eq!(
+            token_types,
+            vec![
+                TokenType::TokenError,
+                TokenType::Plus,
+                TokenType::TokenError,
+                TokenType::Number,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scanner_is_an_iterator() {
+        let mut scanner = Scanner::new("a @ b");
+        assert_eq!(
+            scanner.next().unwrap().unwrap().token_type,
+            TokenType::Identifier
+        );
+        let Some(Err(InterpreterError::ScannerError(diagnostics))) = scanner.next() else {
+            panic!("expected a scanner error");
+        };
+        assert_eq!(diagnostics.errors[0].span, SourceSpan::new(2.into(), 1));
+        assert_eq!(
+            scanner.next().unwrap().unwrap().token_type,
+            TokenType::Identifier
+        );
+        assert_eq!(scanner.next().unwrap().unwrap().token_type, TokenType::Eof);
+        assert!(scanner.next().is_none());
+        assert!(scanner.next().is_none());
+
+        let tokens: Vec<_> = Scanner::new("").collect();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].as_ref().unwrap().token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let source = r#""tab\tnew\nret\rslash\\quote\"nul\0e\u{e9}\u{1F600}" "plain""#;
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        assert_eq!(
+            tokens[0].lexeme.as_deref(),
+            Some("tab\tnew\nret\rslash\\quote\"nul\0eé😀")
+        );
+        assert!(matches!(tokens[0].lexeme, Some(Cow::Owned(_))));
+        assert_eq!(tokens[0].span, SourceSpan::new(0.into(), source.len() - 8));
+        assert!(matches!(tokens[1].lexeme, Some(Cow::Borrowed("plain"))));
+    }
+
+    #[test]
+    fn test_string_escape_errors() {
+        for (source, message, (offset, len)) in [
+            (r#""a\qb""#, "Unknown escape sequence '\\q'.", (2, 2)),
+            (
+                "\"a\\\nb\"",
+                "Unknown escape sequence: line break after '\\'.",
+                (2, 2),
+            ),
+            (r#""a\éb""#, "Unknown escape sequence '\\é'.", (2, 3)),
+            (r#""é\u00e9""#, "Expect '{' after '\\u'.", (3, 2)),
+            (r#""\u{}""#, "Expect hex digits in Unicode escape.", (1, 4)),
+            (
+                r#""\u{12G4}""#,
+                "Expect hex digit or '}' in Unicode escape.",
+                (6, 1),
+            ),
+            (
+                r#""\u{12""#,
+                "Expect hex digit or '}' in Unicode escape.",
+                (6, 1),
+            ),
+            (
+                r#""\u{1234567}""#,
+                "Unicode escape can have at most 6 hex digits.",
+                (1, 11),
+            ),
+            (
+                r#""\u{D800}""#,
+                "'D800' is not a Unicode scalar value.",
+                (1, 8),
+            ),
+            (r#""\u{12"#, "Unterminated string.", (0, 6)),
+            (r#""ends\"#, "Unterminated string.", (0, 6)),
+        ] {
+            let Err(InterpreterError::ScannerError(diagnostics)) =
+                Scanner::new(source).scan_tokens()
+            else {
+                panic!("expected a scanner error for {}", source);
+            };
+            let [diagnostic] = &diagnostics.errors[..] else {
+                panic!("expected one error for {}", source);
+            };
+            assert_eq!(diagnostic.message, message, "for {}", source);
+            assert_eq!(
+                diagnostic.span,
+                SourceSpan::new(offset.into(), len),
+                "for {}",
+                source
+            );
+        }
+
+        // the first bad escape in a string is reported, and scanning carries on after the string
+        let mut scanner = Scanner::new(r#""\a\b" @"#);
+        let Some(Err(InterpreterError::ScannerError(diagnostics))) = scanner.next() else {
+            panic!("expected a scanner error");
+        };
+        assert_eq!(diagnostics.errors[0].span, SourceSpan::new(1.into(), 2));
+        assert!(matches!(scanner.next(), Some(Err(_))));
+        assert_eq!(scanner.next().unwrap().unwrap().token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_interpolation_tokens() {
+        let source = r#""a ${x} b ${ {1} } c ${"d ${y}"}""#;
+        let tokens: Vec<_> = Scanner::new(source)
+            .scan_tokens()
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.token_type, token.lexeme.unwrap_or_default()))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::InterpolationStart, "a ".into()),
+                (TokenType::Identifier, "x".into()),
+                (TokenType::InterpolationSegment, " b ".into()),
+                (TokenType::LeftBrace, "{".into()),
+                (TokenType::Number, "1".into()),
+                (TokenType::RightBrace, "}".into()),
+                (TokenType::InterpolationSegment, " c ".into()),
+                (TokenType::InterpolationStart, "d ".into()),
+                (TokenType::Identifier, "y".into()),
+                (TokenType::InterpolationEnd, "".into()),
+                (TokenType::InterpolationEnd, "".into()),
+                (TokenType::Eof, "".into()),
+            ]
+        );
+        // `$` alone, `\$` and braces outside an interpolation are just characters
+        let tokens = Scanner::new(r#""$x \${y} {}""#).scan_tokens().unwrap();
+        assert_eq!(tokens[0].token_type, TokenType::String);
+        assert_eq!(tokens[0].lexeme.as_deref(), Some("$x ${y} {}"));
+    }
+
+    #[test]
+    fn test_unterminated_interpolation() {
+        let Err(InterpreterError::ScannerError(diagnostics)) =
+            Scanner::new("print \"é ${x + 1;").scan_tokens()
+        else {
+            panic!("expected a scanner error");
+        };
+        let [diagnostic] = &diagnostics.errors[..] else {
+            panic!("expected one error");
+        };
+        assert_eq!(diagnostic.message, "Unterminated string interpolation.");
+        assert_eq!(diagnostic.span, SourceSpan::new(10.into(), 2));
+    }
 }
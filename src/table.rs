@@ -0,0 +1,277 @@
+use crate::object::ObjRef;
+use crate::value::Value;
+
+/// Grow the table once it is this full (counting tombstones).
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    key: Option<ObjRef>,
+    value: Value,
+}
+
+impl Entry {
+    fn empty() -> Self {
+        Self {
+            key: None,
+            value: Value::nil(),
+        }
+    }
+
+    /// A deleted entry: no key, but a non-nil value so probing continues past it.
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !self.value.is_nil()
+    }
+}
+
+/// Hash table keyed by interned strings, using open addressing with linear probing.
+///
+/// Because keys are interned, lookups compare handles rather than characters.
+/// Deleted entries are replaced by tombstones so later probe sequences stay intact.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    // Number of live entries *plus* tombstones.
+    count: usize,
+    // Capacity is always zero or a power of two so we can mask instead of modulo.
+    entries: Vec<Entry>,
+}
+
+fn key_hash(key: &ObjRef) -> u32 {
+    key.as_string().map_or(0, |s| s.hash)
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of live entries.
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.key.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finds the slot for `key`: either the entry holding it, or the slot it should be inserted into
+    /// (preferring the first tombstone seen along the way).
+    fn find_entry(entries: &[Entry], key: &ObjRef) -> usize {
+        let mask = entries.len() - 1;
+        let mut index = key_hash(key) as usize & mask;
+        let mut tombstone = None;
+        loop {
+            let entry = &entries[index];
+            match &entry.key {
+                None if entry.is_tombstone() => {
+                    tombstone.get_or_insert(index);
+                }
+                None => return tombstone.unwrap_or(index),
+                Some(k) if k == key => return index,
+                Some(_) => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::empty(); capacity];
+        // Tombstones are not copied over, so recount.
+        self.count = 0;
+        for entry in self.entries.drain(..) {
+            if let Some(key) = entry.key {
+                let index = Self::find_entry(&entries, &key);
+                entries[index] = entry;
+                self.count += 1;
+            }
+        }
+        self.entries = entries;
+    }
+
+    pub(crate) fn get(&self, key: &ObjRef) -> Option<&Value> {
+        if self.count == 0 {
+            return None;
+        }
+        let entry = &self.entries[Self::find_entry(&self.entries, key)];
+        entry.key.as_ref().map(|_| &entry.value)
+    }
+
+    /// Inserts or overwrites `key`. Returns `true` if the key was not already present.
+    pub(crate) fn set(&mut self, key: ObjRef, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.capacity() as f64 * TABLE_MAX_LOAD {
+            let capacity = (self.capacity() * 2).max(8);
+            self.adjust_capacity(capacity);
+        }
+        let index = Self::find_entry(&self.entries, &key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        // Reusing a tombstone does not change the count: it was already included.
+        if is_new_key && !entry.is_tombstone() {
+            self.count += 1;
+        }
+        entry.key = Some(key);
+        entry.value = value;
+        is_new_key
+    }
+
+    /// Removes `key`, leaving a tombstone in its place. Returns `true` if the key was present.
+    pub(crate) fn delete(&mut self, key: &ObjRef) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+        let index = Self::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+        entry.key = None;
+        entry.value = Value::bool(true);
+        true
+    }
+
+    /// Copies every entry of this table into `to`.
+    pub fn add_all(&self, to: &mut Table) {
+        for entry in &self.entries {
+            if let Some(key) = entry.key {
+                to.set(key, entry.value);
+            }
+        }
+    }
+
+    /// Iterates over the live entries, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (ObjRef, &Value)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, &entry.value)))
+    }
+
+    /// Deletes every entry whose key was not marked by the garbage collector, so the table
+    /// does not keep those keys alive.
+    pub fn remove_white(&mut self) {
+        let white: Vec<ObjRef> = self
+            .iter()
+            .filter(|(key, _)| !key.is_marked())
+            .map(|(key, _)| key)
+            .collect();
+        for key in white {
+            self.delete(&key);
+        }
+    }
+
+    /// Looks up a string key by its contents: this is what makes interning possible.
+    pub(crate) fn find_string(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        if self.count == 0 {
+            return None;
+        }
+        let mask = self.capacity() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                None if !entry.is_tombstone() => return None,
+                Some(key) => {
+                    if let Some(s) = key.as_string() {
+                        if s.hash == hash && s.chars == chars {
+                            return Some(key);
+                        }
+                    }
+                }
+                None => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Heap;
+
+    #[test]
+    fn test_set_get() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let key = heap.copy_string("answer", &());
+        assert!(table.get(&key).is_none());
+        assert!(table.set(key, Value::number(42.0)));
+        assert!(!table.set(key, Value::number(43.0)));
+        assert_eq!(table.get(&key), Some(&Value::number(43.0)));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_growth() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys: Vec<ObjRef> = (0..100)
+            .map(|i| heap.copy_string(&format!("key{}", i), &()))
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            table.set(*key, Value::number(i as f64));
+            assert!(table.count as f64 <= table.capacity() as f64 * TABLE_MAX_LOAD);
+            assert!(table.capacity().is_power_of_two());
+        }
+        assert_eq!(table.len(), 100);
+        assert_eq!(table.capacity(), 256);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(table.get(key), Some(&Value::number(i as f64)));
+        }
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let a = heap.copy_string("a", &());
+        let b = heap.copy_string("b", &());
+        table.set(a, Value::nil());
+        table.set(b, Value::bool(false));
+        assert!(table.delete(&a));
+        assert!(!table.delete(&a));
+        assert!(table.get(&a).is_none());
+        assert_eq!(table.get(&b), Some(&Value::bool(false)));
+        assert_eq!(table.len(), 1);
+        // The tombstone still counts towards the load factor until the table is resized.
+        assert_eq!(table.count, 2);
+    }
+
+    #[test]
+    fn test_collisions() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        // Find three keys which land in the same bucket of a table with 8 slots.
+        let first = heap.copy_string("k0", &());
+        let bucket = key_hash(&first) & 7;
+        let colliding: Vec<ObjRef> = (1..)
+            .map(|i| heap.copy_string(&format!("k{}", i), &()))
+            .filter(|key| key_hash(key) & 7 == bucket)
+            .take(2)
+            .collect();
+        let (second, third) = (colliding[0], colliding[1]);
+
+        table.set(first, Value::number(1.0));
+        table.set(second, Value::number(2.0));
+        table.set(third, Value::number(3.0));
+        assert_eq!(table.capacity(), 8);
+        assert_eq!(table.get(&third), Some(&Value::number(3.0)));
+
+        // Deleting the middle of the probe sequence must not hide the keys after it.
+        table.delete(&second);
+        assert!(table.get(&second).is_none());
+        assert_eq!(table.get(&third), Some(&Value::number(3.0)));
+        assert_eq!(table.find_string("k0", key_hash(&first)), Some(first));
+
+        // Re-inserting reuses the tombstone rather than growing the count.
+        table.set(second, Value::number(4.0));
+        assert_eq!(table.count, 3);
+        assert_eq!(table.get(&second), Some(&Value::number(4.0)));
+    }
+}
@@ -1,5 +1,8 @@
+use std::borrow::Cow;
 use std::fmt;
 
+use miette::SourceSpan;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     // Single-character tokens.
@@ -27,6 +30,11 @@ pub enum TokenType {
     Identifier,
     String,
     Number,
+    // The pieces of an interpolated string, "start ${a} segment ${b} end", around the
+    // tokens of the expressions embedded in it.
+    InterpolationStart,
+    InterpolationSegment,
+    InterpolationEnd,
     // Keywords.
     And,
     Class,
@@ -122,6 +130,9 @@ impl fmt::Display for TokenType {
             TokenType::Identifier => "Identifier",
             TokenType::String => "String",
             TokenType::Number => "Number",
+            TokenType::InterpolationStart => "InterpolationStart",
+            TokenType::InterpolationSegment => "InterpolationSegment",
+            TokenType::InterpolationEnd => "InterpolationEnd",
             TokenType::And => "And",
             TokenType::Class => "Class",
             TokenType::Else => "Else",
@@ -145,36 +156,58 @@ impl fmt::Display for TokenType {
     }
 }
 
+/// A token scanned from source code which lives for `'src`.
 #[derive(Debug, Clone)]
-pub struct Token {
+pub struct Token<'src> {
     pub token_type: TokenType,
-    // a lexeme is not always necessary to get the drift
-    pub lexeme: Option<String>,
+    // Only the end of the source has no lexeme. It is usually a slice of the source, but is
+    // owned when it's not quite what the source says, such as an error message.
+    pub lexeme: Option<Cow<'src, str>>,
+    // where the token starts: both count from 1, and the column counts characters, not bytes
     pub line: usize,
     pub column: usize,
+    // the bytes of the source code the token was scanned from
+    pub span: SourceSpan,
 }
 
-impl Token {
-    pub fn new(token_type: TokenType, lexeme: Option<String>, line: usize, column: usize) -> Self {
+impl<'src> Token<'src> {
+    pub fn new(
+        token_type: TokenType,
+        lexeme: Option<Cow<'src, str>>,
+        line: usize,
+        column: usize,
+        span: SourceSpan,
+    ) -> Self {
         Self {
             token_type,
             lexeme,
             line,
             column,
+            span,
         }
     }
 
-    pub fn end(line: usize, column: usize) -> Self {
+    /// The end of the source: an empty span just past its last byte.
+    pub fn end(line: usize, column: usize, offset: usize) -> Self {
         Self {
             token_type: TokenType::Eof,
             lexeme: None,
             line,
             column,
+            span: SourceSpan::new(offset.into(), 0),
+        }
+    }
+
+    /// Copies the lexeme if it is borrowed, so the token can outlive the source.
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            lexeme: self.lexeme.map(|lexeme| Cow::Owned(lexeme.into_owned())),
+            ..self
         }
     }
 }
 
-impl fmt::Display for Token {
+impl fmt::Display for Token<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -1,57 +1,359 @@
+use std::cmp::Ordering;
 use std::fmt;
 
 use crate::error::CloxersError;
+use crate::memory::Heap;
+use crate::object::{ObjKind, ObjString};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-pub enum Value {
-    Number(f64),
-    Bool(bool),
-    Nil,
+pub use repr::Value;
+
+/// A tagged union: 16 bytes, with the type in its own word.
+///
+/// The variants are private so that the rest of the crate goes through the same constructors
+/// and accessors whichever layout is compiled in.
+#[cfg(not(feature = "nan-boxing"))]
+mod repr {
+    use crate::object::ObjRef;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Repr {
+        Number(f64),
+        Bool(bool),
+        Nil,
+        Obj(ObjRef),
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    pub struct Value(Repr);
+
+    impl Value {
+        pub fn number(n: f64) -> Value {
+            Value(Repr::Number(n))
+        }
+
+        pub fn bool(b: bool) -> Value {
+            Value(Repr::Bool(b))
+        }
+
+        pub fn nil() -> Value {
+            Value(Repr::Nil)
+        }
+
+        pub(crate) fn obj(obj: ObjRef) -> Value {
+            Value(Repr::Obj(obj))
+        }
+
+        pub fn as_number(&self) -> Option<f64> {
+            match self.0 {
+                Repr::Number(n) => Some(n),
+                _ => None,
+            }
+        }
+
+        pub fn as_bool(&self) -> Option<bool> {
+            match self.0 {
+                Repr::Bool(b) => Some(b),
+                _ => None,
+            }
+        }
+
+        pub fn is_nil(&self) -> bool {
+            matches!(self.0, Repr::Nil)
+        }
+
+        pub(crate) fn as_obj(&self) -> Option<ObjRef> {
+            match self.0 {
+                Repr::Obj(obj) => Some(obj),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Every value packed into the 64 bits of a double.
+///
+/// A double whose exponent bits are all set, along with the top bit of the mantissa (and the
+/// one below it, which Intel reserves), is a quiet NaN. Arithmetic never produces one with any
+/// other mantissa bits set, which leaves those for storing everything that is not a number:
+/// small tags for nil, true and false, or, with the sign bit set as well, a 48-bit pointer.
+#[cfg(feature = "nan-boxing")]
+mod repr {
+    use crate::object::ObjRef;
+
+    #[cfg(not(target_pointer_width = "64"))]
+    compile_error!("the nan-boxing feature needs 64-bit pointers");
+
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const NIL_VAL: u64 = QNAN | TAG_NIL;
+    const FALSE_VAL: u64 = QNAN | TAG_FALSE;
+    const TRUE_VAL: u64 = QNAN | TAG_TRUE;
+
+    #[derive(Clone, Copy)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub fn number(n: f64) -> Value {
+            // A NaN with an unusual payload could be mistaken for a tagged value.
+            if n.is_nan() {
+                Value(f64::NAN.to_bits())
+            } else {
+                Value(n.to_bits())
+            }
+        }
+
+        pub fn bool(b: bool) -> Value {
+            Value(if b { TRUE_VAL } else { FALSE_VAL })
+        }
+
+        pub fn nil() -> Value {
+            Value(NIL_VAL)
+        }
+
+        pub(crate) fn obj(obj: ObjRef) -> Value {
+            let bits = obj.to_bits();
+            debug_assert_eq!(bits & (SIGN_BIT | QNAN), 0, "pointer wider than 48 bits");
+            Value(SIGN_BIT | QNAN | bits)
+        }
+
+        pub fn as_number(&self) -> Option<f64> {
+            if self.0 & QNAN != QNAN {
+                Some(f64::from_bits(self.0))
+            } else {
+                None
+            }
+        }
+
+        pub fn as_bool(&self) -> Option<bool> {
+            match self.0 {
+                TRUE_VAL => Some(true),
+                FALSE_VAL => Some(false),
+                _ => None,
+            }
+        }
+
+        pub fn is_nil(&self) -> bool {
+            self.0 == NIL_VAL
+        }
+
+        pub(crate) fn as_obj(&self) -> Option<ObjRef> {
+            if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+                // Safety: only `Value::obj` sets both the sign and quiet NaN bits.
+                Some(unsafe { ObjRef::from_bits(self.0 & !(SIGN_BIT | QNAN)) })
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Numbers compare as doubles, so that NaN is not equal to itself. Everything else is
+    /// equal only if the bits are: objects are compared by identity.
+    impl PartialEq for Value {
+        fn eq(&self, other: &Value) -> bool {
+            match (self.as_number(), other.as_number()) {
+                (Some(a), Some(b)) => a == b,
+                _ => self.0 == other.0,
+            }
+        }
+    }
 }
 
 impl Value {
     pub fn is_falsey(&self) -> bool {
-        matches!(self, Value::Nil | Value::Bool(false))
+        self.is_nil() || self.as_bool() == Some(false)
     }
+
+    /// The string this value refers to, borrowed from the heap that owns it.
+    pub(crate) fn as_string<'h>(&self, heap: &'h Heap) -> Option<&'h ObjString> {
+        match &self.as_obj()?.get(heap).kind {
+            ObjKind::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
     pub fn negate(&self) -> Result<Value, CloxersError> {
-        match self {
-            Value::Number(n) => Ok(Value::Number(-n)),
+        match self.as_number() {
+            Some(n) => Ok(Value::number(-n)),
             _ => Err(CloxersError::TypeError("Cannot negate Nul".to_string())),
         }
     }
 
+    fn numbers(&self, other: &Value) -> Option<(f64, f64)> {
+        Some((self.as_number()?, other.as_number()?))
+    }
+
+    /// Adds two numbers. String concatenation allocates, so the VM handles it before calling this.
     pub fn add(&self, other: &Value) -> Result<Value, CloxersError> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-            _ => Err(CloxersError::TypeError("Operands must be numbers".to_string())),
+        match self.numbers(other) {
+            Some((a, b)) => Ok(Value::number(a + b)),
+            _ => Err(CloxersError::TypeError(
+                "Operands must be two numbers or two strings".to_string(),
+            )),
         }
     }
     pub fn subtract(&self, other: &Value) -> Result<Value, CloxersError> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-            _ => Err(CloxersError::TypeError("Operands must be numbers".to_string())),
+        match self.numbers(other) {
+            Some((a, b)) => Ok(Value::number(a - b)),
+            _ => Err(CloxersError::TypeError(
+                "Operands must be numbers".to_string(),
+            )),
         }
     }
     pub fn multiply(&self, other: &Value) -> Result<Value, CloxersError> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-            _ => Err(CloxersError::TypeError("Operands must be numbers".to_string())),
+        match self.numbers(other) {
+            Some((a, b)) => Ok(Value::number(a * b)),
+            _ => Err(CloxersError::TypeError(
+                "Operands must be numbers".to_string(),
+            )),
         }
     }
     pub fn divide(&self, other: &Value) -> Result<Value, CloxersError> {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a / b)),
-            _ => Err(CloxersError::TypeError("Operands must be numbers".to_string())),
+        match self.numbers(other) {
+            Some((a, b)) => Ok(Value::number(a / b)),
+            _ => Err(CloxersError::TypeError(
+                "Operands must be numbers".to_string(),
+            )),
+        }
+    }
+    pub fn greater(&self, other: &Value) -> Result<Value, CloxersError> {
+        match self.numbers(other) {
+            Some((a, b)) => Ok(Value::bool(a > b)),
+            _ => Err(CloxersError::TypeError(
+                "Operands must be numbers".to_string(),
+            )),
+        }
+    }
+    pub fn less(&self, other: &Value) -> Result<Value, CloxersError> {
+        match self.numbers(other) {
+            Some((a, b)) => Ok(Value::bool(a < b)),
+            _ => Err(CloxersError::TypeError(
+                "Operands must be numbers".to_string(),
+            )),
         }
     }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Bool(b) => write!(f, "{}", b),
-            Value::Nil => write!(f, "nil"),
+        if let Some(n) = self.as_number() {
+            write!(f, "{}", n)
+        } else if let Some(b) = self.as_bool() {
+            write!(f, "{}", b)
+        } else if let Some(obj) = self.as_obj() {
+            write!(f, "{}", obj)
+        } else {
+            write!(f, "nil")
         }
     }
 }
+
+/// Written out by hand so both layouts print the same way.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(n) = self.as_number() {
+            write!(f, "Number({:?})", n)
+        } else if let Some(b) = self.as_bool() {
+            write!(f, "Bool({:?})", b)
+        } else if let Some(obj) = self.as_obj() {
+            write!(f, "Obj({:?})", obj)
+        } else {
+            write!(f, "Nil")
+        }
+    }
+}
+
+/// Only numbers (and, trivially, values of the other primitive types) have an ordering.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        if let Some((a, b)) = self.numbers(other) {
+            a.partial_cmp(&b)
+        } else if let (Some(a), Some(b)) = (self.as_bool(), other.as_bool()) {
+            a.partial_cmp(&b)
+        } else if self.is_nil() && other.is_nil() {
+            Some(Ordering::Equal)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Heap;
+
+    #[test]
+    fn test_round_trip() {
+        let mut heap = Heap::new();
+        let string = heap.copy_string("hi", &());
+        for n in [0.0, -0.0, 1.5, -2.25, f64::INFINITY, f64::MIN_POSITIVE] {
+            let value = Value::number(n);
+            assert_eq!(value.as_number().map(f64::to_bits), Some(n.to_bits()));
+            assert!(value.as_bool().is_none() && value.as_obj().is_none() && !value.is_nil());
+        }
+        assert_eq!(Value::bool(true).as_bool(), Some(true));
+        assert_eq!(Value::bool(false).as_bool(), Some(false));
+        assert!(Value::nil().is_nil());
+        assert!(Value::nil().as_number().is_none());
+        assert_eq!(Value::obj(string).as_obj(), Some(string));
+        assert_eq!(Value::obj(string).as_string(&heap).unwrap().chars, "hi");
+        assert!(Value::obj(string).as_number().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "nan-boxing")]
+    fn test_nan_boxed_size() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn test_nan_is_a_number() {
+        for nan in [f64::NAN, -f64::NAN, f64::from_bits(0x7fff_ffff_ffff_ffff)] {
+            let value = Value::number(nan);
+            assert!(value.as_number().unwrap().is_nan());
+            assert!(!value.is_nil() && value.as_obj().is_none());
+            assert_ne!(value, value);
+        }
+        let zero = Value::number(0.0);
+        assert!(zero.divide(&zero).unwrap().as_number().unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_equality_and_truthiness() {
+        let mut heap = Heap::new();
+        let a = Value::obj(heap.copy_string("a", &()));
+        let b = Value::obj(heap.copy_string("b", &()));
+        assert_eq!(Value::number(0.0), Value::number(-0.0));
+        assert_ne!(Value::number(0.0), Value::bool(false));
+        assert_ne!(Value::nil(), Value::bool(false));
+        assert_eq!(a, Value::obj(heap.copy_string("a", &())));
+        assert_ne!(a, b);
+
+        assert!(Value::nil().is_falsey());
+        assert!(Value::bool(false).is_falsey());
+        assert!(!Value::bool(true).is_falsey());
+        assert!(!Value::number(0.0).is_falsey());
+        assert!(!a.is_falsey());
+    }
+
+    #[test]
+    fn test_operations() {
+        let one = Value::number(1.0);
+        let two = Value::number(2.0);
+        assert_eq!(one.add(&two).unwrap(), Value::number(3.0));
+        assert_eq!(one.negate().unwrap(), Value::number(-1.0));
+        assert_eq!(one.less(&two).unwrap(), Value::bool(true));
+        assert!(one.add(&Value::nil()).is_err());
+        assert!(Value::bool(true).negate().is_err());
+        assert_eq!(
+            format!("{} {} {}", two, Value::bool(true), Value::nil()),
+            "2 true nil"
+        );
+        assert_eq!(format!("{:?}", Value::number(2.5)), "Number(2.5)");
+    }
+}
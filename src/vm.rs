@@ -1,112 +1,978 @@
-use miette::{IntoDiagnostic, Result};
+use std::io::{self, Write};
+
+use miette::{IntoDiagnostic, Result, SourceSpan};
 
 use crate::chunk;
 use crate::error;
+use crate::memory::{GcMode, Heap, Roots};
+use crate::object::{
+    ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjRef, ObjUpvalue,
+    Upvalue,
+};
 use crate::opcodes::OpCode;
+use crate::table::Table;
 use crate::value;
 
-pub struct VM<'a> {
-    chunk: &'a chunk::Chunk,
+/// How deeply calls may nest before we report a stack overflow.
+pub const FRAMES_MAX: usize = 64;
+
+/// An ongoing function call.
+#[derive(Debug)]
+struct CallFrame {
+    closure: ObjRef,
+    // offset of the next instruction to execute in the function's chunk
     ip: usize,
+    // index of the first stack slot this call may use: slot zero holds the callee itself
+    slots: usize,
+}
+
+/// The parts of the VM that refer to heap objects, borrowed so that the heap can be
+/// collected (or handed to the compiler) at the same time.
+pub struct VmRoots<'a> {
+    frames: &'a [CallFrame],
+    stack: &'a [value::Value],
+    globals: &'a Table,
+    open_upvalues: &'a [ObjRef],
+    init_string: ObjRef,
+}
+
+impl Roots for VmRoots<'_> {
+    fn mark_roots(&self, heap: &mut Heap) {
+        for value in self.stack {
+            heap.mark_value(value);
+        }
+        for frame in self.frames {
+            heap.mark_object(frame.closure);
+        }
+        for upvalue in self.open_upvalues {
+            heap.mark_object(*upvalue);
+        }
+        heap.mark_table(self.globals);
+        heap.mark_object(self.init_string);
+    }
+
+    /// Globals are written through the barrier, new open upvalues are allocated black, and
+    /// `init` never changes. Only the stack and the frames need marking again.
+    fn mark_unbarriered_roots(&self, heap: &mut Heap) {
+        for value in self.stack {
+            heap.mark_value(value);
+        }
+        for frame in self.frames {
+            heap.mark_object(frame.closure);
+        }
+    }
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
     stack: Vec<value::Value>,
+    globals: Table,
+    // Upvalues still pointing into the stack, ordered by stack slot: closing them pops from the end.
+    open_upvalues: Vec<ObjRef>,
+    // the name initializers are looked up by, interned once up front
+    init_string: ObjRef,
+    heap: Heap,
+    // where `print` statements write to
+    out: Box<dyn Write>,
 }
 
-impl VM<'_> {
-    pub fn new<'a>(chunk: &'a chunk::Chunk) -> VM<'a> {
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(out: Box<dyn Write>) -> VM {
+        Self::with_gc_mode(out, GcMode::default())
+    }
+
+    /// Creates a VM whose heap is collected in the given mode.
+    pub fn with_gc_mode(out: Box<dyn Write>, mode: GcMode) -> VM {
+        let mut heap = Heap::with_mode(mode);
+        let init_string = heap.copy_string("init", &());
         VM {
-            chunk,
-            ip: 0,
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Vec::new(),
+            globals: Table::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            heap,
+            out,
+        }
+    }
+
+    /// The heap that owns every object this VM (and code compiled for it) allocates.
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// The heap along with the VM's roots, which must be marked by any collection while
+    /// something else (such as the compiler) is allocating on the heap.
+    pub fn heap_and_roots(&mut self) -> (&mut Heap, VmRoots<'_>) {
+        let roots = VmRoots {
+            frames: &self.frames,
+            stack: &self.stack,
+            globals: &self.globals,
+            open_upvalues: &self.open_upvalues,
+            init_string: self.init_string,
+        };
+        (&mut self.heap, roots)
+    }
+
+    /// Allocates on the heap, which may collect first: the VM's roots are kept.
+    fn alloc(&mut self, kind: ObjKind) -> ObjRef {
+        let (heap, roots) = self.heap_and_roots();
+        heap.alloc(kind, &roots)
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        let (heap, roots) = self.heap_and_roots();
+        heap.take_string(chars, &roots)
+    }
+
+    fn collect_garbage(&mut self) {
+        let (heap, roots) = self.heap_and_roots();
+        heap.collect(&roots);
+    }
+
+    pub fn reset_stack(&mut self) {
+        // Closures that escaped the abandoned frames still need their captured values.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    /// Describes the calls in progress, innermost first, as `[line N] in name()`.
+    ///
+    /// After a runtime error this shows where it happened, until the stack is reset.
+    pub fn stack_trace(&self) -> Vec<String> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = Self::function(&frame.closure);
+                // ip has already moved past the instruction which failed
+                let line = function
+                    .and_then(|f| f.chunk.line_at(frame.ip.saturating_sub(1)))
+                    .unwrap_or_default();
+                match function.and_then(|f| f.name) {
+                    Some(name) => format!("[line {}] in {}()", line, name),
+                    None => format!("[line {}] in script", line),
+                }
+            })
+            .collect()
+    }
+
+    /// The source code of the instruction being executed by the innermost call, which after a
+    /// runtime error is the one which failed.
+    pub fn error_span(&self) -> Option<SourceSpan> {
+        let frame = self.frames.last()?;
+        Self::function(&frame.closure)?
+            .chunk
+            .span_at(frame.ip.saturating_sub(1))
+    }
+
+    /// Runs a hand-assembled chunk as the body of a top-level function.
+    pub fn interpret_chunk(&mut self, chunk: chunk::Chunk) -> Result<()> {
+        let mut function = ObjFunction::new(None);
+        function.chunk = chunk;
+        let function = self.alloc(ObjKind::Function(function));
+        self.interpret(function)
+    }
+
+    /// Runs a compiled top-level function.
+    pub(crate) fn interpret(&mut self, function: ObjRef) -> Result<()> {
+        let closure = self.alloc(ObjKind::Closure(ObjClosure::new(function, Vec::new())));
+        self.stack.push(value::Value::obj(closure));
+        self.call(closure, 0)?;
+        self.run()
+    }
+
+    /// The function wrapped by a closure.
+    fn function(closure: &ObjRef) -> Option<&ObjFunction> {
+        closure.as_closure()?.function.as_function()
+    }
+
+    /// The `index`th upvalue captured by the closure of the current call frame.
+    fn frame_upvalue(&mut self, index: u8) -> Result<ObjRef> {
+        let closure = self.frame_mut()?.closure;
+        closure
+            .as_closure()
+            .and_then(|closure| closure.upvalues.get(index as usize))
+            .copied()
+            .ok_or_else(|| {
+                error::CloxersError::BadInstruction(format!("Missing upvalue {}", index))
+            })
+            .into_diagnostic()
+    }
+
+    fn frame_mut(&mut self) -> Result<&mut CallFrame> {
+        self.frames
+            .last_mut()
+            .ok_or_else(|| error::CloxersError::BadInstruction("No call frame".to_string()))
+            .into_diagnostic()
+    }
+
+    fn pop(&mut self) -> Result<value::Value> {
+        self.stack
+            .pop()
+            .ok_or_else(|| error::CloxersError::BadInstruction("Stack underflow".to_string()))
+            .into_diagnostic()
+    }
+
+    fn peek(&self, distance: usize) -> Result<&value::Value> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .and_then(|index| self.stack.get(index))
+            .ok_or_else(|| error::CloxersError::BadInstruction("Stack underflow".to_string()))
+            .into_diagnostic()
+    }
+
+    /// A local variable's slot, relative to the current call frame.
+    fn stack_slot(&mut self, slot: u8) -> Result<&mut value::Value> {
+        let index = self.frame_mut()?.slots + slot as usize;
+        self.stack
+            .get_mut(index)
+            .ok_or_else(|| {
+                error::CloxersError::BadInstruction(format!("Missing stack slot {}", slot))
+            })
+            .into_diagnostic()
+    }
+
+    fn read_byte(&mut self, chunk: &chunk::Chunk) -> Result<u8> {
+        let frame = self.frame_mut()?;
+        let byte = chunk
+            .code()
+            .get(frame.ip)
+            .copied()
+            .ok_or_else(|| {
+                error::CloxersError::BadInstruction(format!("Missing operand at {}", frame.ip))
+            })
+            .into_diagnostic()?;
+        frame.ip += 1;
+        Ok(byte)
+    }
+
+    /// Reads a big-endian 16-bit operand, as used by the jump instructions.
+    fn read_short(&mut self, chunk: &chunk::Chunk) -> Result<u16> {
+        let high = self.read_byte(chunk)?;
+        let low = self.read_byte(chunk)?;
+        Ok(u16::from_be_bytes([high, low]))
+    }
+
+    /// Reads the big-endian 24-bit operand of `ConstantLong` and the other long instructions.
+    fn read_long(&mut self, chunk: &chunk::Chunk) -> Result<usize> {
+        let high = self.read_byte(chunk)?;
+        let low = self.read_short(chunk)?;
+        Ok((high as usize) << 16 | low as usize)
+    }
+
+    /// Reads the operand indexing the constant pool of `op_code`: one byte, or three for the
+    /// long forms of instructions.
+    fn read_index(&mut self, chunk: &chunk::Chunk, op_code: OpCode) -> Result<usize> {
+        if op_code.is_long() {
+            self.read_long(chunk)
+        } else {
+            self.read_byte(chunk).map(usize::from)
+        }
+    }
+
+    fn read_constant(chunk: &chunk::Chunk, index: usize) -> Result<&value::Value> {
+        chunk
+            .read_constant(index)
+            .ok_or_else(|| {
+                error::CloxersError::BadInstruction(format!("Missing constant at index {}", index))
+            })
+            .into_diagnostic()
+    }
+
+    /// Reads a constant which the compiler guarantees is a string, such as a variable name.
+    fn read_string(chunk: &chunk::Chunk, index: usize) -> Result<ObjRef> {
+        let constant = Self::read_constant(chunk, index)?;
+        match constant.as_obj() {
+            Some(obj) if obj.is_string() => Ok(obj),
+            _ => Err(error::CloxersError::BadInstruction(format!(
+                "Expected a string constant, found {}",
+                constant
+            )))
+            .into_diagnostic(),
+        }
+    }
+
+    /// Replaces the callee, below the `arg_count` arguments on top of the stack, with `value`.
+    /// Slot zero of the new frame is `this` for methods and initializers.
+    fn set_callee_slot(&mut self, arg_count: usize, value: value::Value) {
+        let slot = self.stack.len() - arg_count - 1;
+        self.stack[slot] = value;
+    }
+
+    fn call_value(&mut self, callee: value::Value, arg_count: usize) -> Result<()> {
+        match callee.as_obj() {
+            Some(obj) if Self::function(&obj).is_some() => self.call(obj, arg_count),
+            Some(obj) if obj.as_bound_method().is_some() => {
+                let bound = obj.as_bound_method().expect("checked by the match guard");
+                self.set_callee_slot(arg_count, bound.receiver);
+                self.call(bound.method, arg_count)
+            }
+            Some(obj) if obj.as_class().is_some() => {
+                let instance = self.alloc(ObjKind::Instance(ObjInstance::new(obj)));
+                self.set_callee_slot(arg_count, value::Value::obj(instance));
+                let initializer = obj
+                    .as_class()
+                    .and_then(|class| class.methods.borrow().get(&self.init_string).cloned());
+                match initializer.and_then(|initializer| initializer.as_obj()) {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    _ if arg_count != 0 => Err(error::CloxersError::ArityMismatch {
+                        expected: 0,
+                        got: arg_count,
+                    })
+                    .into_diagnostic(),
+                    _ => Ok(()),
+                }
+            }
+            _ => Err(error::CloxersError::NotCallable).into_diagnostic(),
+        }
+    }
+
+    /// Calls the method `name` of `class` with the receiver and arguments already on the stack.
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, arg_count: usize) -> Result<()> {
+        match Self::find_method(class, name) {
+            Some(method) => self.call(method, arg_count),
+            None => Err(error::CloxersError::UndefinedProperty(name.to_string())).into_diagnostic(),
+        }
+    }
+
+    /// Calls the method `name` on the receiver below the `arg_count` arguments on the stack,
+    /// without allocating a bound method for it.
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<()> {
+        let receiver = *self.peek(arg_count)?;
+        let instance = Self::instance(&receiver)
+            .ok_or_else(|| error::CloxersError::TypeError("Only instances have methods".into()))?;
+        let instance = instance.as_instance().expect("checked by Self::instance");
+        // A field holding a function shadows a method of the same name.
+        let field = instance.fields.borrow().get(&name).cloned();
+        if let Some(field) = field {
+            self.set_callee_slot(arg_count, field);
+            return self.call_value(field, arg_count);
+        }
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn find_method(class: ObjRef, name: ObjRef) -> Option<ObjRef> {
+        class.as_class()?.methods.borrow().get(&name)?.as_obj()
+    }
+
+    /// Replaces the instance on top of the stack with its class's method `name`, bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<()> {
+        let method = Self::find_method(class, name)
+            .ok_or_else(|| error::CloxersError::UndefinedProperty(name.to_string()))?;
+        let receiver = *self.peek(0)?;
+        let bound = self.alloc(ObjKind::BoundMethod(ObjBoundMethod::new(receiver, method)));
+        self.pop()?;
+        self.stack.push(value::Value::obj(bound));
+        Ok(())
+    }
+
+    /// The class the compiler left on the stack for a `super` access.
+    fn class(value: &value::Value) -> Result<ObjRef> {
+        match value.as_obj() {
+            Some(obj) if obj.as_class().is_some() => Ok(obj),
+            _ => Err(error::CloxersError::BadInstruction(format!(
+                "Expected a class, found {}",
+                value
+            )))
+            .into_diagnostic(),
+        }
+    }
+
+    /// The object `value` refers to, if it is an instance.
+    fn instance(value: &value::Value) -> Option<ObjRef> {
+        value.as_obj().filter(|obj| obj.as_instance().is_some())
+    }
+
+    /// Pushes a frame for `closure`, whose arguments are the top `arg_count` stack values.
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<()> {
+        let arity = Self::function(&closure).map_or(0, |f| f.arity);
+        if arg_count != arity {
+            return Err(error::CloxersError::ArityMismatch {
+                expected: arity,
+                got: arg_count,
+            })
+            .into_diagnostic();
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(error::CloxersError::StackOverflow).into_diagnostic();
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Returns the upvalue for the variable in stack `slot`, reusing an open one if a closure
+    /// has already captured that variable.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut position = self.open_upvalues.len();
+        for (index, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match upvalue.as_upvalue().and_then(ObjUpvalue::open_slot) {
+                Some(open) if open == slot => return *upvalue,
+                Some(open) if open < slot => break,
+                _ => position = index,
+            }
+        }
+        let upvalue = self.alloc(ObjKind::Upvalue(ObjUpvalue::new(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    /// Moves every variable at or above stack slot `last` into the upvalues that captured it.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let Some(upvalue) = upvalue.as_upvalue() else {
+                break;
+            };
+            match upvalue.open_slot() {
+                Some(slot) if slot >= last => {
+                    let value = self.stack[slot];
+                    self.heap.write_barrier(&value);
+                    *upvalue.location.borrow_mut() = Upvalue::Closed(value);
+                    self.open_upvalues.pop();
+                }
+                _ => break,
+            }
         }
     }
 
     fn run_binary_op(&mut self, op_code: OpCode) -> Result<()> {
-        let b = self.stack.pop().ok_or_else(|| {
-            error::CloxersError::BadInstruction("Stack underflow".to_string())
-        })?;
-        let a = self.stack.pop().ok_or_else(|| {
-            error::CloxersError::BadInstruction("Stack underflow".to_string())
-        })?;
+        let b = self.pop()?;
+        let a = self.pop()?;
         let result = match op_code {
-            OpCode::Add => a.add(&b)?,
+            OpCode::Add => match (a.as_string(&self.heap), b.as_string(&self.heap)) {
+                (Some(a), Some(b)) => {
+                    let chars = format!("{}{}", a.chars, b.chars);
+                    value::Value::obj(self.take_string(chars))
+                }
+                _ => a.add(&b)?,
+            },
             OpCode::Subtract => a.subtract(&b)?,
             OpCode::Multiply => a.multiply(&b)?,
             OpCode::Divide => a.divide(&b)?,
-            _ => return Err(error::CloxersError::TypeError(format!("Unknown binary operator {}", op_code))).into_diagnostic(),
+            OpCode::Greater => a.greater(&b)?,
+            OpCode::Less => a.less(&b)?,
+            _ => {
+                return Err(error::CloxersError::TypeError(format!(
+                    "Unknown binary operator {}",
+                    op_code
+                )))
+                .into_diagnostic()
+            }
         };
         self.stack.push(result);
         Ok(())
     }
 
+    /// Executes instructions until the outermost call frame returns.
     pub fn run(&mut self) -> Result<()> {
-        for bytearray in self.chunk.into_iter() {
-            let [op_code_byte, op1_offset, _op2_offset] = bytearray;
+        loop {
+            // Between instructions every live object is reachable from the roots, so this is
+            // where we collect: allocating only does so under stress.
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            let closure = self.frame_mut()?.closure;
+            let chunk = &Self::function(&closure)
+                .ok_or_else(|| {
+                    error::CloxersError::BadInstruction(format!("{} is not a closure", closure))
+                })?
+                .chunk;
+            let op_code_byte = self.read_byte(chunk)?;
             match OpCode::try_from(op_code_byte) {
                 Ok(op_code) => match op_code {
                     OpCode::Return => {
-                        let val = self.stack.pop().ok_or_else(|| {
-                            error::CloxersError::BadInstruction("Stack underflow".to_string())
+                        let result = self.pop()?;
+                        let frame = self.frames.pop().ok_or_else(|| {
+                            error::CloxersError::BadInstruction("No call frame".to_string())
                         })?;
-                        println!("RETURN: {}", val);
-                        return Ok(())
-                    },
+                        self.close_upvalues(frame.slots);
+                        if self.frames.is_empty() {
+                            // Pop the top-level function itself.
+                            self.pop()?;
+                            return Ok(());
+                        }
+                        // Discard the callee, its arguments and locals.
+                        self.stack.truncate(frame.slots);
+                        self.stack.push(result);
+                    }
                     OpCode::Constant => {
-                        let constant =
-                            self.chunk
-                                .read_constant(op1_offset as usize)
-                                .ok_or_else(|| {
-                                    error::CloxersError::BadInstruction(format!(
-                                        "Missing constant at index {}",
-                                        op1_offset
-                                    ))
-                                })?;
-                        self.stack.push(constant.clone());
+                        let index = self.read_byte(chunk)?;
+                        let constant = Self::read_constant(chunk, index.into())?;
+                        self.stack.push(*constant);
+                    }
+                    OpCode::ConstantLong => {
+                        let index = self.read_long(chunk)?;
+                        let constant = Self::read_constant(chunk, index)?;
+                        self.stack.push(*constant);
                     }
                     OpCode::Negate => {
-                        let val = self.stack.pop()
-                        .ok_or_else(|| {
-                            error::CloxersError::BadInstruction("Stack underflow".to_string())
-                        })?;
-                        let new_val = val.negate()?;
-                        self.stack.push(new_val);
+                        let val = self.pop()?;
+                        self.stack.push(val.negate()?);
+                    }
+                    OpCode::Nil => self.stack.push(value::Value::nil()),
+                    OpCode::True => self.stack.push(value::Value::bool(true)),
+                    OpCode::False => self.stack.push(value::Value::bool(false)),
+                    OpCode::Not => {
+                        let val = self.pop()?;
+                        self.stack.push(value::Value::bool(val.is_falsey()));
+                    }
+                    OpCode::Equal => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        self.stack.push(value::Value::bool(a == b));
                     }
                     OpCode::Add
                     | OpCode::Subtract
                     | OpCode::Multiply
-                    | OpCode::Divide => {
+                    | OpCode::Divide
+                    | OpCode::Greater
+                    | OpCode::Less => {
                         self.run_binary_op(op_code)?;
                     }
-                    _ => todo!(),
+                    OpCode::ToString => {
+                        // Strings are left as they are: only other values need a new one.
+                        if self.peek(0)?.as_string(&self.heap).is_none() {
+                            let val = self.pop()?;
+                            let string = self.take_string(val.to_string());
+                            self.stack.push(value::Value::obj(string));
+                        }
+                    }
+                    OpCode::Print => {
+                        let val = self.pop()?;
+                        writeln!(self.out, "{}", val).into_diagnostic()?;
+                    }
+                    OpCode::Pop => {
+                        self.pop()?;
+                    }
+                    OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        // Only pop once the value is in the table so it stays reachable.
+                        let val = *self.peek(0)?;
+                        self.heap.write_barrier(&value::Value::obj(name));
+                        self.heap.write_barrier(&val);
+                        self.globals.set(name, val);
+                        self.pop()?;
+                    }
+                    OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let val = self.globals.get(&name).cloned().ok_or_else(|| {
+                            error::CloxersError::UndefinedVariable(name.to_string())
+                        })?;
+                        self.stack.push(val);
+                    }
+                    OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let val = *self.peek(0)?;
+                        self.heap.write_barrier(&val);
+                        // Assignment never creates a global: undo the insert and complain.
+                        if self.globals.set(name, val) {
+                            self.globals.delete(&name);
+                            return Err(error::CloxersError::UndefinedVariable(name.to_string()))
+                                .into_diagnostic();
+                        }
+                    }
+                    OpCode::GetLocal => {
+                        let slot = self.read_byte(chunk)?;
+                        let val = *self.stack_slot(slot)?;
+                        self.stack.push(val);
+                    }
+                    OpCode::SetLocal => {
+                        let slot = self.read_byte(chunk)?;
+                        let val = *self.peek(0)?;
+                        *self.stack_slot(slot)? = val;
+                    }
+                    OpCode::Jump => {
+                        let offset = self.read_short(chunk)?;
+                        self.frame_mut()?.ip += offset as usize;
+                    }
+                    OpCode::JumpIfFalse => {
+                        let offset = self.read_short(chunk)?;
+                        if self.peek(0)?.is_falsey() {
+                            self.frame_mut()?.ip += offset as usize;
+                        }
+                    }
+                    OpCode::Loop => {
+                        let offset = self.read_short(chunk)?;
+                        let frame = self.frame_mut()?;
+                        frame.ip = frame
+                            .ip
+                            .checked_sub(offset as usize)
+                            .ok_or_else(|| {
+                                error::CloxersError::BadInstruction(format!(
+                                    "Loop jumps back before the start of the chunk from {}",
+                                    frame.ip
+                                ))
+                            })
+                            .into_diagnostic()?;
+                    }
+                    OpCode::Call => {
+                        let arg_count = self.read_byte(chunk)?;
+                        let callee = *self.peek(arg_count as usize)?;
+                        self.call_value(callee, arg_count as usize)?;
+                    }
+                    OpCode::Closure | OpCode::ClosureLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let constant = Self::read_constant(chunk, index)?;
+                        let function = match constant.as_obj() {
+                            Some(obj) if obj.as_function().is_some() => obj,
+                            _ => {
+                                return Err(error::CloxersError::BadInstruction(format!(
+                                    "Expected a function constant, found {}",
+                                    constant
+                                )))
+                                .into_diagnostic()
+                            }
+                        };
+                        let upvalue_count = function.as_function().map_or(0, |f| f.upvalue_count);
+                        let mut upvalues = Vec::with_capacity(upvalue_count);
+                        for _ in 0..upvalue_count {
+                            let is_local = self.read_byte(chunk)?;
+                            let index = self.read_byte(chunk)?;
+                            let upvalue = if is_local == 1 {
+                                let slot = self.frame_mut()?.slots + index as usize;
+                                self.capture_upvalue(slot)
+                            } else {
+                                self.frame_upvalue(index)?
+                            };
+                            upvalues.push(upvalue);
+                        }
+                        let closure =
+                            self.alloc(ObjKind::Closure(ObjClosure::new(function, upvalues)));
+                        self.stack.push(value::Value::obj(closure));
+                    }
+                    OpCode::GetUpvalue => {
+                        let index = self.read_byte(chunk)?;
+                        let upvalue = self.frame_upvalue(index)?;
+                        let location = upvalue
+                            .as_upvalue()
+                            .map(|u| u.location.borrow().clone())
+                            .ok_or_else(|| {
+                                error::CloxersError::BadInstruction(format!(
+                                    "{} is not an upvalue",
+                                    upvalue
+                                ))
+                            })?;
+                        let val = match location {
+                            Upvalue::Open(slot) => self.stack[slot],
+                            Upvalue::Closed(val) => val,
+                        };
+                        self.stack.push(val);
+                    }
+                    OpCode::SetUpvalue => {
+                        let index = self.read_byte(chunk)?;
+                        let upvalue = self.frame_upvalue(index)?;
+                        let val = *self.peek(0)?;
+                        if let Some(upvalue) = upvalue.as_upvalue() {
+                            let mut location = upvalue.location.borrow_mut();
+                            match &mut *location {
+                                Upvalue::Open(slot) => self.stack[*slot] = val,
+                                Upvalue::Closed(closed) => {
+                                    self.heap.write_barrier(&val);
+                                    *closed = val;
+                                }
+                            }
+                        }
+                    }
+                    OpCode::Class | OpCode::ClassLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let class = self.alloc(ObjKind::Class(ObjClass::new(name)));
+                        self.stack.push(value::Value::obj(class));
+                    }
+                    OpCode::GetProperty | OpCode::GetPropertyLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let instance = Self::instance(self.peek(0)?).ok_or_else(|| {
+                            error::CloxersError::TypeError("Only instances have properties".into())
+                        })?;
+                        let instance = instance.as_instance().expect("checked by Self::instance");
+                        let field = instance.fields.borrow().get(&name).cloned();
+                        match field {
+                            Some(val) => {
+                                self.pop()?;
+                                self.stack.push(val);
+                            }
+                            None => self.bind_method(instance.class, name)?,
+                        }
+                    }
+                    OpCode::SetProperty | OpCode::SetPropertyLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let instance = Self::instance(self.peek(1)?).ok_or_else(|| {
+                            error::CloxersError::TypeError("Only instances have fields".into())
+                        })?;
+                        let val = self.pop()?;
+                        if let Some(instance) = instance.as_instance() {
+                            self.heap.write_barrier(&value::Value::obj(name));
+                            self.heap.write_barrier(&val);
+                            instance.fields.borrow_mut().set(name, val);
+                        }
+                        // Replace the instance with the assigned value: assignment is an expression.
+                        self.pop()?;
+                        self.stack.push(val);
+                    }
+                    OpCode::Method | OpCode::MethodLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let method = *self.peek(0)?;
+                        self.heap.write_barrier(&value::Value::obj(name));
+                        self.heap.write_barrier(&method);
+                        if let Some(class) = self.peek(1)?.as_obj() {
+                            if let Some(class) = class.as_class() {
+                                class.methods.borrow_mut().set(name, method);
+                            }
+                        }
+                        self.pop()?;
+                    }
+                    OpCode::Inherit => {
+                        let superclass = match self.peek(1)?.as_obj() {
+                            Some(obj) if obj.as_class().is_some() => obj,
+                            _ => {
+                                return Err(error::CloxersError::TypeError(
+                                    "Superclass must be a class".into(),
+                                ))
+                                .into_diagnostic()
+                            }
+                        };
+                        // Copy the inherited methods down: methods the subclass defines
+                        // afterwards override them. Shading the superclass shades its methods.
+                        self.heap.write_barrier(&value::Value::obj(superclass));
+                        if let Some(subclass) = self.peek(0)?.as_obj() {
+                            if let (Some(superclass), Some(subclass)) =
+                                (superclass.as_class(), subclass.as_class())
+                            {
+                                superclass
+                                    .methods
+                                    .borrow()
+                                    .add_all(&mut subclass.methods.borrow_mut());
+                            }
+                        }
+                        self.pop()?;
+                    }
+                    OpCode::GetSuper | OpCode::GetSuperLong => {
+                        let index = self.read_index(chunk, op_code)?;
+                        let name = Self::read_string(chunk, index)?;
+                        let superclass = Self::class(&self.pop()?)?;
+                        self.bind_method(superclass, name)?;
+                    }
+                    OpCode::SuperInvoke => {
+                        let index = self.read_byte(chunk)?;
+                        let name = Self::read_string(chunk, index.into())?;
+                        let arg_count = self.read_byte(chunk)?;
+                        let superclass = Self::class(&self.pop()?)?;
+                        self.invoke_from_class(superclass, name, arg_count as usize)?;
+                    }
+                    OpCode::Invoke => {
+                        let index = self.read_byte(chunk)?;
+                        let name = Self::read_string(chunk, index.into())?;
+                        let arg_count = self.read_byte(chunk)?;
+                        self.invoke(name, arg_count as usize)?;
+                    }
+                    OpCode::CloseUpvalue => {
+                        // The variable to close is on top of the stack.
+                        self.close_upvalues(self.stack.len() - 1);
+                        self.pop()?;
+                    }
                 },
-                Err(err) => todo!(),
+                Err(_) => {
+                    return Err(error::CloxersError::OpCodeError { code: op_code_byte })
+                        .into_diagnostic()
+                }
             }
         }
-        Ok(())
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::chunk::Chunk;
+    use crate::object::ObjKind;
     use crate::opcodes::OpCode;
     use crate::value::Value;
 
+    /// Runs a hand-assembled chunk as a top-level function. The value left on top of the
+    /// stack is stored in a global so the test can inspect it after the frame has returned.
+    fn run_chunk(vm: &mut VM, mut chunk: Chunk) -> Result<Value> {
+        let name = vm.heap.copy_string("result", &());
+        let name = chunk.add_constant(Value::obj(name)) as u8;
+        chunk.write(OpCode::DefineGlobal.into(), 99);
+        chunk.write(name, 99);
+        chunk.write(OpCode::Nil.into(), 99);
+        chunk.write(OpCode::Return.into(), 99);
+        let mut function = ObjFunction::new(None);
+        function.chunk = chunk;
+        let function = vm.heap.alloc(ObjKind::Function(function), &());
+        vm.interpret(function)?;
+        let name = vm.heap.copy_string("result", &());
+        Ok(vm.globals.get(&name).cloned().unwrap_or(Value::nil()))
+    }
+
     #[test]
     fn test_vm() {
         let mut chunk = Chunk::new();
-        let _ = chunk.write_constant(Value::Number(1.2), 1);
-        let _ = chunk.write_constant(Value::Number(3.4), 1);
-        let _ = chunk.write(OpCode::Add.into(), 1);
-        let _ = chunk.write_constant(Value::Number(5.6), 2);
-        let _ = chunk.write(OpCode::Divide.into(), 4);
-        let mut vm = VM::new(&chunk);
-        vm.run().unwrap();
-        assert_eq!(vm.stack.len(), 1);
-        let result = vm.stack.pop().unwrap();
-        let close_enough = Value::Number(0.8214285714285714);
+        let _ = chunk.write_constant(Value::number(1.2), 1);
+        let _ = chunk.write_constant(Value::number(3.4), 1);
+        chunk.write(OpCode::Add.into(), 1);
+        let _ = chunk.write_constant(Value::number(5.6), 2);
+        chunk.write(OpCode::Divide.into(), 4);
+        let mut vm = VM::new();
+        let result = run_chunk(&mut vm, chunk).unwrap();
+        assert!(vm.stack.is_empty());
+        let close_enough = Value::number(0.8214285714285714);
         let close_enough = result.subtract(&close_enough).unwrap();
-        assert!(close_enough <= Value::Number(0.0000000000001));
+        assert!(close_enough <= Value::number(0.0000000000001));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_vm_comparison() {
+        // !(1 < 2) == false
+        let mut chunk = Chunk::new();
+        let _ = chunk.write_constant(Value::number(1.0), 1);
+        let _ = chunk.write_constant(Value::number(2.0), 1);
+        chunk.write(OpCode::Less.into(), 1);
+        chunk.write(OpCode::Not.into(), 1);
+        chunk.write(OpCode::False.into(), 1);
+        chunk.write(OpCode::Equal.into(), 1);
+        let mut vm = VM::new();
+        assert_eq!(run_chunk(&mut vm, chunk).unwrap(), Value::bool(true));
+    }
+
+    #[test]
+    fn test_vm_concatenate_strings() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        let hello = vm.heap.copy_string("hello ", &());
+        let world = vm.heap.copy_string("world", &());
+        let _ = chunk.write_constant(Value::obj(hello), 1);
+        let _ = chunk.write_constant(Value::obj(world), 1);
+        chunk.write(OpCode::Add.into(), 1);
+        let expected = vm.heap.copy_string("hello world", &());
+        let result = run_chunk(&mut vm, chunk).unwrap();
+        assert_eq!(result, Value::obj(expected));
+        assert_eq!(result.to_string(), "hello world");
+    }
+
+    #[test]
+    fn test_vm_add_string_and_number() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        let hello = vm.heap.copy_string("hello", &());
+        let _ = chunk.write_constant(Value::obj(hello), 1);
+        let _ = chunk.write_constant(Value::number(1.0), 1);
+        chunk.write(OpCode::Add.into(), 1);
+        assert!(run_chunk(&mut vm, chunk).is_err());
+    }
+
+    #[test]
+    fn test_vm_globals() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        let name = vm.heap.copy_string("answer", &());
+        let name = chunk.add_constant(Value::obj(name)) as u8;
+        let _ = chunk.write_constant(Value::number(42.0), 1);
+        chunk.write(OpCode::DefineGlobal.into(), 1);
+        chunk.write(name, 1);
+        chunk.write(OpCode::GetGlobal.into(), 2);
+        chunk.write(name, 2);
+        assert_eq!(run_chunk(&mut vm, chunk).unwrap(), Value::number(42.0));
+    }
+
+    #[test]
+    fn test_vm_undefined_global() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        let name = vm.heap.copy_string("missing", &());
+        let name = chunk.add_constant(Value::obj(name)) as u8;
+        chunk.write(OpCode::Nil.into(), 1);
+        chunk.write(OpCode::SetGlobal.into(), 1);
+        chunk.write(name, 1);
+        let err = run_chunk(&mut vm, chunk).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined variable 'missing'");
+        // The failed assignment must not leave the variable behind.
+        assert!(vm.globals.is_empty());
+    }
+
+    #[test]
+    fn test_vm_compare_non_numbers() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil.into(), 1);
+        let _ = chunk.write_constant(Value::number(2.0), 1);
+        chunk.write(OpCode::Greater.into(), 1);
+        let mut vm = VM::new();
+        assert!(run_chunk(&mut vm, chunk).is_err());
+    }
+
+    #[test]
+    fn test_vm_call_function() {
+        let mut vm = VM::new();
+        // fun add(a, b) { return a + b; }
+        let mut add = ObjFunction::new(Some(vm.heap.copy_string("add", &())));
+        add.arity = 2;
+        add.chunk.write(OpCode::GetLocal.into(), 1);
+        add.chunk.write(1, 1);
+        add.chunk.write(OpCode::GetLocal.into(), 1);
+        add.chunk.write(2, 1);
+        add.chunk.write(OpCode::Add.into(), 1);
+        add.chunk.write(OpCode::Return.into(), 1);
+        let add = vm.heap.alloc(ObjKind::Function(add), &());
+        // add(1, 2) * 3
+        let mut chunk = Chunk::new();
+        let add = chunk.add_constant(Value::obj(add)) as u8;
+        chunk.write(OpCode::Closure.into(), 2);
+        chunk.write(add, 2);
+        let _ = chunk.write_constant(Value::number(1.0), 2);
+        let _ = chunk.write_constant(Value::number(2.0), 2);
+        chunk.write(OpCode::Call.into(), 2);
+        chunk.write(2, 2);
+        let _ = chunk.write_constant(Value::number(3.0), 2);
+        chunk.write(OpCode::Multiply.into(), 2);
+        assert_eq!(run_chunk(&mut vm, chunk).unwrap(), Value::number(9.0));
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn test_vm_call_errors() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        let _ = chunk.write_constant(Value::number(1.0), 1);
+        chunk.write(OpCode::Call.into(), 1);
+        chunk.write(0, 1);
+        let err = run_chunk(&mut vm, chunk).unwrap_err();
+        assert_eq!(err.to_string(), "Can only call functions and classes");
+        assert_eq!(vm.stack_trace(), vec!["[line 1] in script"]);
+
+        let mut vm = VM::new();
+        let mut function = ObjFunction::new(Some(vm.heap.copy_string("f", &())));
+        function.arity = 1;
+        let function = vm.heap.alloc(ObjKind::Function(function), &());
+        let mut chunk = Chunk::new();
+        let function = chunk.add_constant(Value::obj(function)) as u8;
+        chunk.write(OpCode::Closure.into(), 1);
+        chunk.write(function, 1);
+        chunk.write(OpCode::Call.into(), 1);
+        chunk.write(0, 1);
+        let err = run_chunk(&mut vm, chunk).unwrap_err();
+        assert_eq!(err.to_string(), "Expected 1 arguments but got 0");
+    }
+}